@@ -0,0 +1,307 @@
+//! Conversion of terminal output for Discord `ansi` code blocks.
+//!
+//! Discord only renders a small subset of SGR codes: reset, bold, underline
+//! and the eight basic foreground and background colors. Everything else is
+//! either mapped onto that subset or removed.
+
+const ESC: char = '\u{1b}';
+
+enum Part {
+    Text(String),
+    /// Select Graphic Rendition with its parameters.
+    Sgr(Vec<u32>),
+    /// Any other escape sequence, which is dropped.
+    Other,
+}
+
+/// Splits `text` into plain text and escape sequences.
+fn parse(text: &str) -> Vec<Part> {
+    let mut parts = vec![];
+    let mut plain = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            // Terminals emit CRLF; Discord only needs the LF.
+            if c == '\r' && chars.peek() == Some(&'\n') {
+                continue;
+            }
+            plain.push(c);
+            continue;
+        }
+
+        if !plain.is_empty() {
+            parts.push(Part::Text(std::mem::take(&mut plain)));
+        }
+
+        match chars.next() {
+            Some('[') => {
+                let mut params = String::new();
+                let mut last = None;
+                for c in chars.by_ref() {
+                    if ('\u{40}'..='\u{7e}').contains(&c) {
+                        last = Some(c);
+                        break;
+                    }
+                    params.push(c);
+                }
+                if last == Some('m') {
+                    let params = params
+                        .split(';')
+                        .map(|param| param.parse().unwrap_or(0))
+                        .collect();
+                    parts.push(Part::Sgr(params));
+                } else {
+                    parts.push(Part::Other);
+                }
+            }
+            Some(']') => {
+                // Operating system command, terminated by BEL or ESC \.
+                while let Some(c) = chars.next() {
+                    if c == '\u{7}' {
+                        break;
+                    }
+                    if c == ESC && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+                parts.push(Part::Other);
+            }
+            _ => parts.push(Part::Other),
+        }
+    }
+
+    if !plain.is_empty() {
+        parts.push(Part::Text(plain));
+    }
+    parts
+}
+
+/// Maps an RGB color onto the closest of the eight basic colors.
+fn basic_color(r: u32, g: u32, b: u32) -> u32 {
+    (r > 127) as u32 | ((g > 127) as u32) << 1 | ((b > 127) as u32) << 2
+}
+
+/// Maps a 256-color palette index onto the eight basic colors.
+fn palette_color(index: u32) -> u32 {
+    match index {
+        0..=7 => index,
+        8..=15 => index - 8,
+        16..=231 => {
+            let index = index - 16;
+            let scale = |v: u32| if v == 0 { 0 } else { 55 + v * 40 };
+            basic_color(scale(index / 36), scale(index / 6 % 6), scale(index % 6))
+        }
+        _ => {
+            if index >= 244 {
+                7
+            } else {
+                0
+            }
+        }
+    }
+}
+
+/// Attributes set by the SGR sequences seen so far, in Discord's subset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Style {
+    bold: bool,
+    underline: bool,
+    foreground: Option<u32>,
+    background: Option<u32>,
+}
+
+impl Style {
+    /// Parameters that select this style after a reset.
+    fn params(&self) -> Vec<u32> {
+        let mut params = vec![0];
+        if self.bold {
+            params.push(1);
+        }
+        if self.underline {
+            params.push(4);
+        }
+        params.extend(self.foreground);
+        params.extend(self.background);
+        params
+    }
+}
+
+/// Translates SGR parameters into the ones Discord understands, updating
+/// `style` along the way.
+///
+/// Discord cannot turn single attributes off, so codes like 22 (normal
+/// intensity) or 39 (default color) become a reset followed by the
+/// attributes that are still active.
+fn translate(style: &mut Style, params: &[u32]) -> Vec<u32> {
+    let mut translated = vec![];
+    let mut reset = false;
+    let mut params = params.iter().copied();
+
+    while let Some(param) = params.next() {
+        match param {
+            0 => {
+                *style = Style::default();
+                translated.push(0);
+            }
+            1 => {
+                style.bold = true;
+                translated.push(1);
+            }
+            4 => {
+                style.underline = true;
+                translated.push(4);
+            }
+            22 => {
+                style.bold = false;
+                reset = true;
+            }
+            24 => {
+                style.underline = false;
+                reset = true;
+            }
+            39 => {
+                style.foreground = None;
+                reset = true;
+            }
+            49 => {
+                style.background = None;
+                reset = true;
+            }
+            30..=37 | 90..=97 => {
+                let color = 30 + (param - 30) % 60;
+                style.foreground = Some(color);
+                translated.push(color);
+            }
+            40..=47 | 100..=107 => {
+                let color = 40 + (param - 40) % 60;
+                style.background = Some(color);
+                translated.push(color);
+            }
+            38 | 48 => {
+                let base = if param == 38 { 30 } else { 40 };
+                let color = match params.next() {
+                    Some(5) => params.next().map(palette_color),
+                    Some(2) => match (params.next(), params.next(), params.next()) {
+                        (Some(r), Some(g), Some(b)) => Some(basic_color(r, g, b)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let Some(color) = color {
+                    if param == 38 {
+                        style.foreground = Some(base + color);
+                    } else {
+                        style.background = Some(base + color);
+                    }
+                    translated.push(base + color);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if reset {
+        style.params()
+    } else {
+        translated
+    }
+}
+
+/// Rewrites `text` so that it renders in a Discord `ansi` code block.
+pub fn to_discord(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut style = Style::default();
+    for part in parse(text) {
+        match part {
+            Part::Text(plain) => result.push_str(&plain),
+            Part::Sgr(params) => {
+                let params = translate(&mut style, &params);
+                if !params.is_empty() {
+                    let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                    result.push(ESC);
+                    result.push('[');
+                    result.push_str(&params.join(";"));
+                    result.push('m');
+                }
+            }
+            Part::Other => {}
+        }
+    }
+    result
+}
+
+/// Removes every escape sequence from `text`.
+pub fn strip(text: &str) -> String {
+    parse(text)
+        .into_iter()
+        .filter_map(|part| match part {
+            Part::Text(plain) => Some(plain),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_text_and_escapes() {
+        let parts = parse("a\u{1b}[1;31mb\u{1b}[Kc\u{1b}]0;title\u{7}d\u{1b}[m\r\n");
+        let parts: Vec<String> = parts
+            .into_iter()
+            .map(|part| match part {
+                Part::Text(plain) => plain,
+                Part::Sgr(params) => format!("{:?}", params),
+                Part::Other => "other".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            ["a", "[1, 31]", "b", "other", "c", "other", "d", "[0]", "\n"]
+        );
+    }
+
+    #[test]
+    fn translates_colors() {
+        let mut style = Style::default();
+        assert_eq!(translate(&mut style, &[1, 91, 104, 3]), [1, 31, 44]);
+        assert_eq!(translate(&mut style, &[38, 5, 196]), [31]);
+        assert_eq!(translate(&mut style, &[48, 2, 0, 200, 0]), [42]);
+        assert_eq!(
+            style,
+            Style {
+                bold: true,
+                underline: false,
+                foreground: Some(31),
+                background: Some(42),
+            }
+        );
+    }
+
+    #[test]
+    fn translates_attribute_resets() {
+        let mut style = Style::default();
+        translate(&mut style, &[1, 4, 32, 45]);
+        assert_eq!(translate(&mut style, &[22]), [0, 4, 32, 45]);
+        assert_eq!(translate(&mut style, &[24]), [0, 32, 45]);
+        assert_eq!(translate(&mut style, &[39]), [0, 45]);
+        assert_eq!(translate(&mut style, &[49, 1]), [0, 1]);
+        assert_eq!(translate(&mut style, &[0]), [0]);
+        assert_eq!(style, Style::default());
+    }
+
+    #[test]
+    fn converts_for_discord() {
+        let text = "\u{1b}[1;92mok\u{1b}[22m done\u{1b}[2K\u{1b}[0m";
+        assert_eq!(to_discord(text), "\u{1b}[1;32mok\u{1b}[0;32m done\u{1b}[0m");
+    }
+
+    #[test]
+    fn strips_escapes() {
+        let text = "\u{1b}[31mred\u{1b}[0m\r\n\u{1b}]8;;url\u{1b}\\link\u{1b}]8;;\u{1b}\\";
+        assert_eq!(strip(text), "red\nlink");
+    }
+}
@@ -0,0 +1,555 @@
+//! HTTP API exposing the sandbox to tools other than Discord.
+//!
+//! Every request needs an `Authorization: Bearer <token>` header.
+//!
+//! - `GET /languages` lists the configured languages.
+//! - `POST /run` runs a job and answers with its result.
+//! - `POST /run/stream` answers with newline-delimited JSON events: output
+//!   chunks as they are produced, followed by the result.
+
+use std::{
+    convert::Infallible,
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, Limited, StreamBody};
+use hyper::{
+    body::{Body, Frame},
+    header,
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use poise::serenity_prelude::futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::Notify, task::JoinHandle};
+
+use crate::{
+    config::{Config, HttpConfig},
+    error::BotError,
+    executor::{ExecutionResult, Executor, Job, JobFile, JobLimits, Phase},
+    image::{ImageManager, ImageState},
+    scheduler::{Permit, Scheduler},
+};
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY: usize = 8 * 1024 * 1024;
+
+type ResponseBody = BoxBody<Bytes, Infallible>;
+
+#[derive(Deserialize)]
+struct RunRequest {
+    /// Name or alias of the language, as used in code blocks.
+    language: String,
+    source: String,
+    #[serde(default)]
+    stdin: Option<String>,
+    #[serde(default)]
+    files: Vec<FileBody>,
+    #[serde(default)]
+    downloads: Vec<String>,
+    #[serde(default)]
+    color: Option<bool>,
+}
+
+/// A file with base64 encoded content.
+#[derive(Serialize, Deserialize)]
+struct FileBody {
+    path: String,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct RunResponse {
+    compile_output: String,
+    output: String,
+    timed_out: bool,
+    /// Output was cut off at the output limit.
+    truncated: bool,
+    /// Requested downloads; paths that do not exist are left out.
+    downloads: Vec<FileBody>,
+}
+
+impl From<ExecutionResult> for RunResponse {
+    fn from(result: ExecutionResult) -> Self {
+        Self {
+            compile_output: result.compile_output,
+            output: result.output,
+            timed_out: result.timed_out,
+            truncated: result.truncated,
+            downloads: result
+                .downloads
+                .into_iter()
+                .filter_map(|download| {
+                    Some(FileBody {
+                        content: STANDARD.encode(download.data?),
+                        path: download.path,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StreamEvent {
+    Compile { data: String },
+    Run { data: String },
+    Result(RunResponse),
+    Error { message: String },
+}
+
+#[derive(Serialize)]
+struct LanguageInfo {
+    name: String,
+    aliases: Vec<String>,
+    ready: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Cancels a streamed job when its response is dropped, for example because
+/// the client went away.
+struct CancelOnDrop(Arc<Notify>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.notify_one();
+    }
+}
+
+/// Guild under which API jobs are scheduled, so that they share the
+/// per-guild limit. Discord never uses 0 as an id.
+const API_GUILD: u64 = 0;
+/// Scheduler users of API jobs start here, above every Discord id, so that
+/// each request is its own user.
+const API_USER_BASE: u64 = 1 << 63;
+
+#[derive(Clone)]
+pub struct Api {
+    pub token: String,
+    pub config: Arc<Mutex<Config>>,
+    pub executor: Executor,
+    pub images: ImageManager,
+    pub scheduler: Scheduler,
+    pub next_request: Arc<AtomicU64>,
+}
+
+impl Api {
+    /// Serves the API on `http.bind` until the process exits.
+    pub fn spawn(
+        http: HttpConfig,
+        config: Arc<Mutex<Config>>,
+        executor: Executor,
+        images: ImageManager,
+        scheduler: Scheduler,
+    ) -> JoinHandle<()> {
+        let api = Api {
+            token: http.token,
+            config,
+            executor,
+            images,
+            scheduler,
+            next_request: Arc::new(AtomicU64::new(0)),
+        };
+        tokio::spawn(async move {
+            let listener = match TcpListener::bind(&http.bind).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    log::error!("Failed to bind HTTP API to {}: {}", http.bind, e);
+                    return;
+                }
+            };
+            println!("HTTP API listening on {}", http.bind);
+
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::error!("Failed to accept HTTP connection: {}", e);
+                        continue;
+                    }
+                };
+                let api = api.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(|request| {
+                        let api = api.clone();
+                        async move { Ok::<_, Infallible>(api.handle(request).await) }
+                    });
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        log::error!("HTTP connection failed: {}", e);
+                    }
+                });
+            }
+        })
+    }
+
+    async fn handle<B>(&self, request: Request<B>) -> Response<ResponseBody>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        if !self.is_authorized(&request) {
+            return error(StatusCode::UNAUTHORIZED, "Missing or invalid token.");
+        }
+
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/languages") => self.languages().await,
+            (&Method::POST, "/run") => self.run(request, false).await,
+            (&Method::POST, "/run/stream") => self.run(request, true).await,
+            _ => error(StatusCode::NOT_FOUND, "Not found."),
+        }
+    }
+
+    fn is_authorized<B>(&self, request: &Request<B>) -> bool {
+        request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                !self.token.is_empty() && constant_time_eq(token.as_bytes(), self.token.as_bytes())
+            })
+    }
+
+    /// Waits for a scheduler slot for one API job.
+    async fn admit(&self) -> Permit {
+        let user = API_USER_BASE + self.next_request.fetch_add(1, Ordering::Relaxed);
+        self.scheduler
+            .enqueue(user, Some(API_GUILD))
+            .admitted()
+            .await
+    }
+
+    async fn languages(&self) -> Response<ResponseBody> {
+        let languages = self.config.lock().await.languages.clone();
+        let mut infos = vec![];
+        for language in languages {
+            infos.push(LanguageInfo {
+                ready: self.images.state(&language) == ImageState::Ready,
+                name: language.name,
+                aliases: language.code,
+            });
+        }
+        json(StatusCode::OK, &infos)
+    }
+
+    async fn run<B>(&self, request: Request<B>, streaming: bool) -> Response<ResponseBody>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let body = match Limited::new(request.into_body(), MAX_BODY).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        let request: RunRequest = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        let job = match self.job(request).await {
+            Ok(job) => job,
+            Err(response) => return response,
+        };
+
+        let permit = self.admit().await;
+        let execution = match self.executor.start(job).await {
+            Ok(execution) => execution,
+            Err(e) => return failure(e),
+        };
+        let cancel = Arc::new(Notify::new());
+
+        if !streaming {
+            let result = execution.run(cancel, None).await;
+            drop(permit);
+            return match result {
+                Ok(result) => json(StatusCode::OK, &RunResponse::from(result)),
+                Err(e) => failure(e),
+            };
+        }
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let guard = CancelOnDrop(cancel.clone());
+        let handle = tokio::spawn(async move {
+            let result = execution.run(cancel, Some(tx)).await;
+            drop(permit);
+            result
+        });
+
+        let chunks = stream::unfold(rx, |mut rx| async move {
+            let chunk = rx.recv().await?;
+            let data = chunk.output.to_string();
+            let event = match chunk.phase {
+                Phase::Compile => StreamEvent::Compile { data },
+                Phase::Run => StreamEvent::Run { data },
+            };
+            Some((event, rx))
+        });
+        let result = stream::once(async move {
+            let event = match handle.await {
+                Ok(Ok(result)) => StreamEvent::Result(result.into()),
+                Ok(Err(e)) => {
+                    if e.is_internal() {
+                        log::error!("HTTP job failed: {}", e);
+                    }
+                    StreamEvent::Error {
+                        message: e.user_message(),
+                    }
+                }
+                Err(e) => StreamEvent::Error {
+                    message: e.to_string(),
+                },
+            };
+            drop(guard);
+            event
+        });
+        let lines = chunks.chain(result).map(|event| {
+            let mut line = serde_json::to_vec(&event).unwrap_or_default();
+            line.push(b'\n');
+            Ok(Frame::data(Bytes::from(line)))
+        });
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/x-ndjson")
+            .body(BodyExt::boxed(StreamBody::new(lines)))
+            .unwrap()
+    }
+
+    /// Turns a request into a job, or into the response rejecting it.
+    async fn job(&self, request: RunRequest) -> Result<Job, Response<ResponseBody>> {
+        let config = self.config.lock().await.clone();
+        let Some(language) = config.get_language(&request.language) else {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                &format!("Unknown language: {}", request.language),
+            ));
+        };
+        if self.images.state(&language) != ImageState::Ready {
+            return Err(error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &format!("{} is not available right now.", language.name),
+            ));
+        }
+
+        let mut files = vec![];
+        for file in request.files {
+            match STANDARD.decode(&file.content) {
+                Ok(data) => files.push(JobFile {
+                    path: file.path,
+                    data,
+                }),
+                Err(e) => {
+                    return Err(error(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Invalid content of {}: {}", file.path, e),
+                    ))
+                }
+            }
+        }
+
+        Ok(Job {
+            color: request
+                .color
+                .unwrap_or(language.color.unwrap_or(config.color_output)),
+            language,
+            source: request.source,
+            files,
+            stdin: request.stdin.map(String::into_bytes),
+            downloads: request.downloads,
+            limits: JobLimits {
+                timeout: Duration::from_secs(config.run_timeout),
+                output_bytes: config.max_output_bytes,
+            },
+        })
+    }
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<ResponseBody> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)).boxed())
+        .unwrap()
+}
+
+/// A response for a failed job. Details of internal errors are only logged.
+fn failure(e: BotError) -> Response<ResponseBody> {
+    let status = match &e {
+        BotError::User(_) => StatusCode::BAD_REQUEST,
+        BotError::Config(_) => StatusCode::SERVICE_UNAVAILABLE,
+        BotError::Docker(_) | BotError::Discord(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if e.is_internal() {
+        log::error!("HTTP job failed: {}", e);
+    }
+    error(status, &e.user_message())
+}
+
+/// Compares without returning early, so the time taken does not reveal how
+/// much of a token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn error(status: StatusCode, message: &str) -> Response<ResponseBody> {
+    json(
+        status,
+        &ErrorBody {
+            error: message.to_string(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        container_pool::ContainerPool,
+        runtime::fake::{self, FakeRuntime},
+        scheduler::SchedulerLimits,
+    };
+
+    async fn api(runtime: &Arc<FakeRuntime>) -> Api {
+        let language = fake::language("text", "cat", None);
+        let config: Config =
+            serde_yaml::from_str("{token: '', prefix: '', owner: 0, languages: []}").unwrap();
+        let config = Config {
+            languages: vec![language],
+            ..config
+        };
+        let host = fake::host(runtime);
+        let images = ImageManager::new(vec![host.clone()]);
+        images.prepare(&config.languages).await;
+        Api {
+            token: "secret".to_string(),
+            config: Arc::new(Mutex::new(config)),
+            executor: Executor::new(ContainerPool::new(
+                vec![host],
+                "test".to_string(),
+                Duration::from_secs(600),
+                Duration::from_secs(3600),
+            )),
+            images,
+            scheduler: Scheduler::new(SchedulerLimits {
+                global: 4,
+                per_user: 1,
+                per_guild: 2,
+            }),
+            next_request: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn request(method: Method, path: &str, body: &str) -> Request<Full<Bytes>> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+
+    async fn body(response: Response<ResponseBody>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_token() {
+        let api = api(&Arc::new(FakeRuntime::new())).await;
+        let request = Request::builder()
+            .uri("/languages")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+
+        let response = api.handle(request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_token() {
+        let api = api(&Arc::new(FakeRuntime::new())).await;
+        for token in ["Bearer secreT", "Bearer secret2", "Bearer "] {
+            let request = Request::builder()
+                .uri("/languages")
+                .header(header::AUTHORIZATION, token)
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+
+            let response = api.handle(request).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_languages() {
+        let api = api(&Arc::new(FakeRuntime::new())).await;
+
+        let response = api.handle(request(Method::GET, "/languages", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body(response).await,
+            r#"[{"name":"text","aliases":["text"],"ready":true}]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_code() {
+        let api = api(&Arc::new(FakeRuntime::new())).await;
+        let run = r#"{"language": "text", "source": "", "stdin": "hello"}"#;
+
+        let response = api.handle(request(Method::POST, "/run", run)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body(response).await,
+            r#"{"compile_output":"","output":"hello","timed_out":false,"truncated":false,"downloads":[]}"#
+        );
+
+        let run = r#"{"language": "cobol", "source": ""}"#;
+        let response = api.handle(request(Method::POST, "/run", run)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn waits_for_scheduler() {
+        let api = api(&Arc::new(FakeRuntime::new())).await;
+        let permits = [api.admit().await, api.admit().await];
+        let run = r#"{"language": "text", "source": "", "stdin": "hello"}"#;
+
+        let response = api.handle(request(Method::POST, "/run", run));
+        tokio::pin!(response);
+        let waited = tokio::time::timeout(Duration::from_millis(50), &mut response).await;
+        assert!(waited.is_err());
+
+        drop(permits);
+        assert_eq!(response.await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn streams_output() {
+        let api = api(&Arc::new(FakeRuntime::new())).await;
+        let run = r#"{"language": "text", "source": "", "stdin": "hello"}"#;
+
+        let response = api.handle(request(Method::POST, "/run/stream", run)).await;
+        let body = body(response).await;
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines[0], r#"{"type":"run","data":"hello"}"#);
+        assert!(lines[1].starts_with(r#"{"type":"result","#));
+    }
+}
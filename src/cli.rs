@@ -0,0 +1,285 @@
+//! Command line mode for running code without Discord.
+
+use std::{fs, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config::Config,
+    container_pool::ContainerPool,
+    executor::{Executor, Job, JobLimits},
+    hosts::{self, Host},
+    image::{ImageManager, ImageState},
+    language::Language,
+    scheduler::{Scheduler, SchedulerLimits},
+    selftest,
+};
+
+pub const USAGE: &str = "Usage:
+  rs-docker-bot                                    start the Discord bot
+  rs-docker-bot run --lang <language> <file> [--stdin <file>]
+  rs-docker-bot check-languages
+  rs-docker-bot self-test";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Bot,
+    Run {
+        language: String,
+        file: String,
+        stdin: Option<String>,
+    },
+    CheckLanguages,
+    SelfTest,
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let Some(command) = args.next() else {
+        return Ok(Command::Bot);
+    };
+
+    match command.as_str() {
+        "run" => {
+            let mut language = None;
+            let mut file = None;
+            let mut stdin = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--lang" => language = args.next(),
+                    "--stdin" => stdin = args.next(),
+                    _ if file.is_none() && !arg.starts_with("--") => file = Some(arg),
+                    _ => return Err(format!("Unexpected argument: {}", arg)),
+                }
+            }
+            Ok(Command::Run {
+                language: language.ok_or("Missing --lang")?,
+                file: file.ok_or("Missing source file")?,
+                stdin,
+            })
+        }
+        "check-languages" | "self-test" => match args.next() {
+            Some(arg) => Err(format!("Unexpected argument: {}", arg)),
+            None if command == "self-test" => Ok(Command::SelfTest),
+            None => Ok(Command::CheckLanguages),
+        },
+        _ => Err(format!("Unknown command: {}", command)),
+    }
+}
+
+/// Runs a command other than [`Command::Bot`] and returns the exit code.
+pub async fn run(command: Command, config: Config) -> i32 {
+    let hosts = match hosts::connect(&config).await {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return 1;
+        }
+    };
+
+    let result = match command {
+        Command::Bot => return 0,
+        Command::Run {
+            language,
+            file,
+            stdin,
+        } => run_file(&config, hosts, &language, &file, stdin.as_deref()).await,
+        Command::CheckLanguages => check_languages(&config, hosts).await,
+        Command::SelfTest => self_test(&config, hosts).await,
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            1
+        }
+    }
+}
+
+/// An executor that does not keep containers around. The pool is shared
+/// with a bot running on the same daemon only through the instance label,
+/// so nothing must be left behind when the process exits.
+fn executor(config: &Config, hosts: Vec<Arc<Host>>) -> Executor {
+    Executor::new(ContainerPool::new(
+        hosts,
+        config.instance_id(),
+        Duration::from_secs(config.demand_window),
+        Duration::from_secs(config.container_max_age),
+    ))
+}
+
+/// `language` without warm containers, see [`executor`].
+fn without_pool(language: &Language) -> Language {
+    Language {
+        pool_min: 0,
+        pool_max: 0,
+        ..language.clone()
+    }
+}
+
+fn job(config: &Config, language: &Language, source: String, stdin: Option<Vec<u8>>) -> Job {
+    Job {
+        language: without_pool(language),
+        source,
+        files: vec![],
+        stdin,
+        downloads: vec![],
+        color: language.color.unwrap_or(config.color_output),
+        limits: JobLimits {
+            timeout: Duration::from_secs(config.run_timeout),
+            output_bytes: config.max_output_bytes,
+        },
+    }
+}
+
+async fn prepare(hosts: Vec<Arc<Host>>, language: &Language) -> Result<()> {
+    let images = ImageManager::new(hosts);
+    images.prepare(std::slice::from_ref(language)).await;
+    match images.state(language) {
+        ImageState::Ready => Ok(()),
+        _ => Err(anyhow!(
+            "Image {} is unavailable",
+            language.image_reference()
+        )),
+    }
+}
+
+async fn run_file(
+    config: &Config,
+    hosts: Vec<Arc<Host>>,
+    language: &str,
+    file: &str,
+    stdin: Option<&str>,
+) -> Result<i32> {
+    let language = config
+        .get_language(&language.to_string())
+        .with_context(|| format!("Unknown language: {}", language))?;
+    let source = fs::read_to_string(file).with_context(|| format!("Failed to read {}", file))?;
+    let stdin = match stdin {
+        Some(path) => Some(fs::read(path).with_context(|| format!("Failed to read {}", path))?),
+        None => None,
+    };
+
+    prepare(hosts.clone(), &language).await?;
+    let result = executor(config, hosts)
+        .run(job(config, &language, source, stdin))
+        .await?;
+
+    if !result.compile_output.is_empty() {
+        eprint!("{}", result.compile_output);
+    }
+    print!("{}", result.output);
+    if result.truncated {
+        eprintln!("Output limit reached");
+        return Ok(1);
+    }
+    if result.timed_out {
+        eprintln!("Timeout");
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+/// Runs the test program of every language one after another, pulling each
+/// image only when its language is reached. Unlike `self-test`, a missing
+/// image fails the check.
+async fn check_languages(config: &Config, hosts: Vec<Arc<Host>>) -> Result<i32> {
+    let executor = executor(config, hosts.clone());
+    let images = ImageManager::new(hosts);
+    let mut failed = 0;
+
+    for language in &config.languages {
+        let language = without_pool(language);
+        images.prepare(std::slice::from_ref(&language)).await;
+        let report = selftest::run(
+            &executor,
+            &images,
+            &language,
+            JobLimits {
+                timeout: Duration::from_secs(config.run_timeout),
+                output_bytes: config.max_output_bytes,
+            },
+        )
+        .await;
+        println!("{}", report);
+        if matches!(report.outcome, selftest::Outcome::Failed(_))
+            || images.state(&language) != ImageState::Ready
+        {
+            failed += 1;
+        }
+    }
+
+    println!(
+        "{} of {} languages ok",
+        config.languages.len() - failed,
+        config.languages.len()
+    );
+    Ok(if failed == 0 { 0 } else { 1 })
+}
+
+/// Runs the configured test program of every language.
+async fn self_test(config: &Config, hosts: Vec<Arc<Host>>) -> Result<i32> {
+    let executor = executor(config, hosts.clone());
+    let images = ImageManager::new(hosts);
+    images.prepare(&config.languages).await;
+    let languages: Vec<Language> = config.languages.iter().map(without_pool).collect();
+
+    // Nothing else runs here, so the tests may take every job slot.
+    let scheduler = Scheduler::new(SchedulerLimits {
+        global: config.max_concurrent_jobs,
+        per_user: 1,
+        per_guild: config.max_concurrent_jobs,
+    });
+
+    let reports = selftest::run_all(
+        &executor,
+        &scheduler,
+        &images,
+        &languages,
+        JobLimits {
+            timeout: Duration::from_secs(config.run_timeout),
+            output_bytes: config.max_output_bytes,
+        },
+    )
+    .await;
+    println!("{}", selftest::summarize(&reports));
+
+    let failed = reports
+        .iter()
+        .any(|report| matches!(report.outcome, selftest::Outcome::Failed(_)));
+    Ok(if failed { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(args("")), Ok(Command::Bot));
+        assert_eq!(
+            parse(args("run --lang rust main.rs --stdin in.txt")),
+            Ok(Command::Run {
+                language: "rust".to_string(),
+                file: "main.rs".to_string(),
+                stdin: Some("in.txt".to_string()),
+            })
+        );
+        assert_eq!(parse(args("check-languages")), Ok(Command::CheckLanguages));
+        assert_eq!(parse(args("self-test")), Ok(Command::SelfTest));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(args("run main.rs")).is_err());
+        assert!(parse(args("run --lang rust")).is_err());
+        assert!(parse(args("run --lang rust a.rs b.rs")).is_err());
+        assert!(parse(args("check-languages --all")).is_err());
+        assert!(parse(args("serve")).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use poise::{serenity_prelude as serenity, ChoiceParameter, CreateReply};
+
+use crate::{
+    config::TriggerMode,
+    executor::JobLimits,
+    guild_settings::GuildSettings,
+    output::{escape_code_block, MESSAGE_LIMIT},
+    selftest, Context, Error,
+};
+
+/// Configures where and how code is run in this server.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    default_member_permissions = "MANAGE_GUILD",
+    subcommands(
+        "show",
+        "allow_channel",
+        "disallow_channel",
+        "enable_language",
+        "disable_language",
+        "trigger",
+        "limits"
+    )
+)]
+pub async fn sandbox(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+fn describe(settings: &GuildSettings) -> String {
+    let channels = if settings.allowed_channels.is_empty() {
+        "all".to_string()
+    } else {
+        settings
+            .allowed_channels
+            .iter()
+            .map(|channel| format!("<#{}>", channel))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let languages = if settings.enabled_languages.is_empty() {
+        "all".to_string()
+    } else {
+        settings.enabled_languages.join(", ")
+    };
+    let limit = |limit: Option<String>| limit.unwrap_or_else(|| "default".to_string());
+
+    format!(
+        "Channels: {}\nLanguages: {}\nTrigger: {}\nRuns per minute: {}\nCPU seconds per day: {}",
+        channels,
+        languages,
+        settings
+            .trigger
+            .map_or("default".to_string(), |trigger| trigger.name().to_string()),
+        limit(settings.limits.runs_per_minute.map(|l| l.to_string())),
+        limit(settings.limits.cpu_seconds_per_day.map(|l| l.to_string())),
+    )
+}
+
+async fn update<F>(ctx: Context<'_>, f: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut GuildSettings),
+{
+    let guild = ctx.guild_id().ok_or("This command only works in servers")?;
+    let settings = ctx.data().guild_settings.update(guild.get(), f).await?;
+    ctx.say(describe(&settings)).await?;
+    Ok(())
+}
+
+/// Shows the settings of this server.
+#[poise::command(slash_command, guild_only)]
+pub async fn show(ctx: Context<'_>) -> Result<(), Error> {
+    let guild = ctx.guild_id().ok_or("This command only works in servers")?;
+    let settings = ctx.data().guild_settings.get(guild.get()).await;
+    ctx.say(describe(&settings)).await?;
+    Ok(())
+}
+
+/// Allows running code in a channel. Once a channel is allowed, all others are not.
+#[poise::command(slash_command, guild_only)]
+pub async fn allow_channel(ctx: Context<'_>, channel: serenity::GuildChannel) -> Result<(), Error> {
+    update(ctx, |settings| {
+        if !settings.allowed_channels.contains(&channel.id.get()) {
+            settings.allowed_channels.push(channel.id.get());
+        }
+    })
+    .await
+}
+
+/// Removes a channel from the allowed channels.
+#[poise::command(slash_command, guild_only)]
+pub async fn disallow_channel(
+    ctx: Context<'_>,
+    channel: serenity::GuildChannel,
+) -> Result<(), Error> {
+    update(ctx, |settings| {
+        settings.allowed_channels.retain(|c| *c != channel.id.get());
+    })
+    .await
+}
+
+async fn language_name(ctx: Context<'_>, language: &str) -> Result<String, Error> {
+    let config = ctx.data().config.lock().await;
+    config
+        .get_language(&language.to_string())
+        .map(|language| language.name)
+        .ok_or_else(|| format!("Unknown language: {}", language).into())
+}
+
+/// Enables a language. Once a language is enabled, all others are disabled.
+#[poise::command(slash_command, guild_only)]
+pub async fn enable_language(ctx: Context<'_>, language: String) -> Result<(), Error> {
+    let name = language_name(ctx, &language).await?;
+    update(ctx, |settings| {
+        if !settings.enabled_languages.contains(&name) {
+            settings.enabled_languages.push(name);
+        }
+    })
+    .await
+}
+
+/// Removes a language from the enabled languages.
+#[poise::command(slash_command, guild_only)]
+pub async fn disable_language(ctx: Context<'_>, language: String) -> Result<(), Error> {
+    let name = language_name(ctx, &language).await?;
+    update(ctx, |settings| {
+        settings.enabled_languages.retain(|l| *l != name);
+    })
+    .await
+}
+
+/// Sets which messages cause code to run. Leave empty to use the default.
+#[poise::command(slash_command, guild_only)]
+pub async fn trigger(ctx: Context<'_>, mode: Option<TriggerMode>) -> Result<(), Error> {
+    update(ctx, |settings| settings.trigger = mode).await
+}
+
+/// Lowers the rate limit and daily CPU quota of this server.
+#[poise::command(slash_command, guild_only)]
+pub async fn limits(
+    ctx: Context<'_>,
+    runs_per_minute: Option<usize>,
+    cpu_seconds_per_day: Option<u64>,
+) -> Result<(), Error> {
+    update(ctx, |settings| {
+        settings.limits.runs_per_minute = runs_per_minute;
+        settings.limits.cpu_seconds_per_day = cpu_seconds_per_day;
+    })
+    .await
+}
+
+/// Runs the test program of every language and reports the results.
+#[poise::command(slash_command, owners_only)]
+pub async fn selftest(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer().await?;
+    let config = ctx.data().config.lock().await.clone();
+    let reports = selftest::run_all(
+        &ctx.data().executor,
+        &ctx.data().scheduler,
+        &ctx.data().images,
+        &config.languages,
+        JobLimits {
+            timeout: Duration::from_secs(config.run_timeout),
+            output_bytes: config.max_output_bytes,
+        },
+    )
+    .await;
+    let summary = selftest::summarize(&reports);
+
+    let content = format!("```\n{}\n```", escape_code_block(&summary));
+    let reply = if content.chars().count() <= MESSAGE_LIMIT {
+        CreateReply::default().content(content)
+    } else {
+        CreateReply::default()
+            .content(summary.lines().last().unwrap_or_default())
+            .attachment(serenity::CreateAttachment::bytes(summary, "selftest.txt"))
+    };
+    ctx.send(reply).await?;
+    Ok(())
+}
@@ -1,6 +1,27 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
 use serde::{Deserialize, Serialize};
 
-use crate::language::Language;
+use crate::{language::Language, limits::LimitsConfig};
+
+/// Decides which messages with a code block cause it to be run.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, poise::ChoiceParameter,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerMode {
+    /// Every message consisting of a code block.
+    #[default]
+    Always,
+    /// Only messages starting with the prefix.
+    Prefix,
+    /// Only messages starting with a mention of the bot.
+    Mention,
+    /// Only messages in explicitly allowed channels.
+    Allowlist,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
@@ -8,9 +29,260 @@ pub struct Config {
     pub prefix: String,
     pub languages: Vec<Language>,
     pub owner: u64,
+    /// Identifies this bot instance on a shared Docker daemon. Containers are
+    /// labeled with it so that cleanup only touches our own containers.
+    /// Defaults to one derived from the bot account, see
+    /// [`Config::instance_id`]; set it when one bot account runs several
+    /// instances on the same daemon.
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    /// Seconds after which a container that is neither pooled nor in use is
+    /// considered leaked and removed by the reaper.
+    #[serde(default = "default_container_ttl")]
+    pub container_ttl: u64,
+    /// Seconds between two reaper passes.
+    #[serde(default = "default_reaper_interval")]
+    pub reaper_interval: u64,
+    /// Seconds of request history used to decide how many containers to keep
+    /// warm for each language.
+    #[serde(default = "default_demand_window")]
+    pub demand_window: u64,
+    /// Seconds between two pool rebalancing passes.
+    #[serde(default = "default_rebalance_interval")]
+    pub rebalance_interval: u64,
+    /// Number of languages whose pools are warmed concurrently at startup.
+    #[serde(default = "default_warmup_concurrency")]
+    pub warmup_concurrency: usize,
+    /// Seconds after which an idle pooled container is replaced.
+    #[serde(default = "default_container_max_age")]
+    pub container_max_age: u64,
+    /// Jobs allowed to execute at the same time across all users.
+    #[serde(default = "default_max_concurrent_jobs")]
+    pub max_concurrent_jobs: usize,
+    /// Jobs a single user may have executing at the same time.
+    #[serde(default = "default_max_jobs_per_user")]
+    pub max_jobs_per_user: usize,
+    /// Jobs a single guild may have executing at the same time.
+    #[serde(default = "default_max_jobs_per_guild")]
+    pub max_jobs_per_guild: usize,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// File the per-guild settings are persisted in.
+    #[serde(default = "default_guild_settings_path")]
+    pub guild_settings_path: String,
+    #[serde(default)]
+    pub trigger: TriggerMode,
+    /// Channels code is run in when the trigger mode is `allowlist`, in
+    /// addition to the channels allowed per guild.
+    #[serde(default)]
+    pub allowed_channels: Vec<u64>,
+    /// Run commands in a terminal so compilers emit colored diagnostics, and
+    /// show the results in `ansi` code blocks.
+    #[serde(default)]
+    pub color_output: bool,
+    /// Seconds a program may run before it is stopped.
+    #[serde(default = "default_run_timeout")]
+    pub run_timeout: u64,
+    /// Bytes of output kept from compiling and from running a program. The
+    /// program is stopped once it wrote more.
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// Serves the HTTP API when set.
+    #[serde(default)]
+    pub http: Option<HttpConfig>,
+    #[serde(default)]
+    pub docker: DockerConfig,
+    /// Docker daemons jobs are spread over. When empty, jobs run on the
+    /// daemon configured in `docker`.
+    #[serde(default)]
+    pub hosts: Vec<HostConfig>,
+    /// Isolation of job containers. Languages can override each option.
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+}
+
+/// How job containers are isolated from the host.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SandboxConfig {
+    /// OCI runtime registered with the daemon, such as `runsc` (gVisor) or
+    /// `kata-runtime`. Defaults to the daemon's default runtime.
+    #[serde(default)]
+    pub runtime: Option<String>,
+    /// Path to a seccomp profile in JSON, read by the bot.
+    #[serde(default)]
+    pub seccomp_profile: Option<String>,
+    /// Name of an AppArmor profile loaded on the Docker host.
+    #[serde(default)]
+    pub apparmor_profile: Option<String>,
+}
+
+impl SandboxConfig {
+    /// These options, falling back to `defaults` for the unset ones.
+    pub fn or(&self, defaults: &SandboxConfig) -> SandboxConfig {
+        SandboxConfig {
+            runtime: self.runtime.clone().or(defaults.runtime.clone()),
+            seccomp_profile: self
+                .seccomp_profile
+                .clone()
+                .or(defaults.seccomp_profile.clone()),
+            apparmor_profile: self
+                .apparmor_profile
+                .clone()
+                .or(defaults.apparmor_profile.clone()),
+        }
+    }
+
+    /// The `security_opt` entries of a container using these options.
+    pub fn security_opt(&self) -> Result<Vec<String>> {
+        let mut options = vec![];
+        if let Some(path) = &self.seccomp_profile {
+            let profile = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read seccomp profile {}", path))?;
+            serde_json::from_str::<serde_json::Value>(&profile)
+                .with_context(|| format!("Invalid seccomp profile {}", path))?;
+            options.push(format!("seccomp={}", profile));
+        }
+        if let Some(profile) = &self.apparmor_profile {
+            options.push(format!("apparmor={}", profile));
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub docker: DockerConfig,
+    /// Share of the jobs this host gets relative to the other hosts.
+    #[serde(default = "default_host_weight")]
+    pub weight: usize,
+    /// Matched against the `host_labels` of languages.
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+/// How to reach the Docker daemon.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DockerConfig {
+    /// `unix:///path/to/docker.sock` (or just the path), `tcp://host:port`,
+    /// `http://host:port` or `https://host:port`. Defaults to `DOCKER_HOST`
+    /// and then to the local socket.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Client certificates for TCP connections. Defaults to the files in
+    /// `DOCKER_CERT_PATH` when `DOCKER_TLS_VERIFY` is set.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Seconds before a request to the daemon times out.
+    #[serde(default = "default_docker_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            tls: None,
+            timeout: default_docker_timeout(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub ca: String,
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpConfig {
+    /// Address to listen on, such as `127.0.0.1:8080`.
+    pub bind: String,
+    /// Bearer token every request has to present.
+    pub token: String,
+}
+
+fn default_container_ttl() -> u64 {
+    600
+}
+
+fn default_reaper_interval() -> u64 {
+    60
+}
+
+fn default_demand_window() -> u64 {
+    600
+}
+
+fn default_rebalance_interval() -> u64 {
+    60
+}
+
+fn default_warmup_concurrency() -> usize {
+    4
+}
+
+fn default_container_max_age() -> u64 {
+    3600
+}
+
+fn default_max_concurrent_jobs() -> usize {
+    8
+}
+
+fn default_max_jobs_per_user() -> usize {
+    1
+}
+
+fn default_max_jobs_per_guild() -> usize {
+    4
+}
+
+fn default_run_timeout() -> u64 {
+    120
+}
+
+fn default_max_output_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_docker_timeout() -> u64 {
+    120
+}
+
+fn default_host_weight() -> usize {
+    1
+}
+
+fn default_guild_settings_path() -> String {
+    "guilds.yaml".to_string()
 }
 
 impl Config {
+    /// The configured instance id, or one derived from the id of the bot
+    /// account. The first part of a bot token is its user id in base64, so
+    /// the default stays the same across restarts and differs between bots.
+    pub fn instance_id(&self) -> String {
+        if let Some(instance_id) = &self.instance_id {
+            return instance_id.clone();
+        }
+
+        let encoded = self.token.split('.').next().unwrap_or_default();
+        let bot = STANDARD_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .ok()
+            .and_then(|id| String::from_utf8(id).ok())
+            .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+            .unwrap_or_else(|| encoded.to_string());
+        if bot.is_empty() {
+            "dockerbot".to_string()
+        } else {
+            format!("dockerbot-{}", bot)
+        }
+    }
+
     pub fn get_language(&self, name: &String) -> Option<Language> {
         for language in self.languages.iter() {
             if language.code.contains(name) {
@@ -21,3 +293,38 @@ impl Config {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sample_config() {
+        let config: Config = serde_yaml::from_str(include_str!("../sample-config.yaml")).unwrap();
+        let rust = config.get_language(&"rust".to_string()).unwrap();
+        assert_eq!(rust.test.unwrap().expected_output, "ok");
+        assert!(config.http.is_none());
+    }
+
+    #[test]
+    fn derives_instance_id_from_bot() {
+        let config = |yaml: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "{{prefix: '', owner: 0, languages: [], {}}}",
+                yaml
+            ))
+            .unwrap()
+        };
+
+        // "MTIzNDU2" is the bot id 123456 in base64.
+        let bot = config("token: MTIzNDU2.GhIjKl.secret");
+        assert_eq!(bot.instance_id(), "dockerbot-123456");
+        let other = config("token: Nzg5.GhIjKl.secret");
+        assert_eq!(other.instance_id(), "dockerbot-789");
+        assert_eq!(config("token: ''").instance_id(), "dockerbot");
+        assert_eq!(
+            config("token: MTIzNDU2.GhIjKl.secret, instance_id: staging").instance_id(),
+            "staging"
+        );
+    }
+}
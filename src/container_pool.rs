@@ -1,88 +1,795 @@
-use std::sync::Arc;
-
-use bollard::{
-    container::{RemoveContainerOptions, StopContainerOptions},
-    Docker,
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Deref,
+    sync::{Arc, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::Mutex;
+
+use anyhow::Result;
+use futures_util::{stream, StreamExt};
+use tokio::{runtime::Handle, sync::Mutex, task::JoinHandle};
 
 use crate::{
-    docker::{docker_ps, Container},
+    docker::{instance_label, Container, LABEL_CREATED},
+    error::BotError,
+    hosts::Host,
     language::Language,
 };
 
+/// A container handed out by `get_container`.
+pub struct RunningContainer {
+    pub host: String,
+    /// Alive as long as any handle to the container is.
+    pub token: Weak<()>,
+}
+
+type Running = Arc<Mutex<HashMap<String, RunningContainer>>>;
+
+/// A container handed out by the pool.
+///
+/// The container is removed when the lease is released, or in a background
+/// task when the lease is dropped without being released (for example when
+/// the handler returns early with an error).
+pub struct ContainerLease {
+    container: Option<Container>,
+    running: Running,
+}
+
+impl ContainerLease {
+    /// Removes the container and waits for the removal to finish.
+    pub async fn release(mut self) {
+        if let Some(container) = self.container.take() {
+            Self::remove(container, self.running.clone()).await;
+        }
+    }
+
+    async fn remove(container: Container, running: Running) {
+        if let Err(e) = container.stop().await {
+            log::error!("Failed to remove container {}: {}", container.id, e);
+        }
+        running.lock().await.remove(&container.id);
+    }
+}
+
+impl Deref for ContainerLease {
+    type Target = Container;
+
+    fn deref(&self) -> &Container {
+        self.container
+            .as_ref()
+            .expect("container lease used after release")
+    }
+}
+
+impl Drop for ContainerLease {
+    fn drop(&mut self) {
+        if let Some(container) = self.container.take() {
+            let running = self.running.clone();
+            if let Ok(handle) = Handle::try_current() {
+                handle.spawn(Self::remove(container, running));
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ContainerPool {
+    pub hosts: Vec<Arc<Host>>,
     pub containers: Arc<Mutex<Vec<Container>>>,
+    /// Containers handed out by `get_container`, keyed by id.
+    pub running: Running,
+    /// Recent `get_container` calls per image, used to size the pools.
+    pub demand: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+    /// Number of containers currently being created for each image.
+    pub warming: Arc<Mutex<HashMap<String, usize>>>,
+    pub instance_id: String,
+    pub demand_window: Duration,
+    /// Pooled containers older than this are replaced by fresh ones.
+    pub max_age: Duration,
 }
 
 impl ContainerPool {
-    pub fn new() -> Self {
+    pub fn new(
+        hosts: Vec<Arc<Host>>,
+        instance_id: String,
+        demand_window: Duration,
+        max_age: Duration,
+    ) -> Self {
         Self {
+            hosts,
             containers: Arc::new(Mutex::new(vec![])),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            demand: Arc::new(Mutex::new(HashMap::new())),
+            warming: Arc::new(Mutex::new(HashMap::new())),
+            instance_id,
+            demand_window,
+            max_age,
         }
     }
 
-    pub async fn get_container(&mut self, language: Language) -> Container {
-        let mut pool = self.containers.lock().await;
-        if let Some(i) = pool.iter().position(|container| {
-            if let Some(l) = &container.language {
-                l.image == language.image
+    pub async fn get_container(&self, language: Language) -> Result<ContainerLease> {
+        self.record_demand(&language).await;
+        // Counts towards the load of the host while the container is being
+        // created, so that concurrent jobs spread over the hosts.
+        let reservation = Arc::new(());
+        let pending = format!("pending-{}", uuid::Uuid::new_v4());
+        let host = self
+            .least_loaded_host(&language, &pending, &reservation)
+            .await?;
+        let container = self.take_container(language, &host).await;
+        let mut running = self.running.lock().await;
+        running.remove(&pending);
+        let container = container?;
+        running.insert(
+            container.id.clone(),
+            RunningContainer {
+                host: host.name.clone(),
+                token: Arc::downgrade(&container.token),
+            },
+        );
+        drop(running);
+        Ok(ContainerLease {
+            container: Some(container),
+            running: self.running.clone(),
+        })
+    }
+
+    /// Picks the host for the next job of `language`: the one running the
+    /// fewest containers relative to its weight. The host is charged with
+    /// `reservation` under the key `pending` until that is removed.
+    async fn least_loaded_host(
+        &self,
+        language: &Language,
+        pending: &str,
+        reservation: &Arc<()>,
+    ) -> Result<Arc<Host>> {
+        let mut running = self.running.lock().await;
+        let host = self.pick_host(language, |host: &Host| {
+            running
+                .values()
+                .filter(|container| container.host == host.name)
+                .filter(|container| container.token.strong_count() > 0)
+                .count()
+        })?;
+        running.insert(
+            pending.to_string(),
+            RunningContainer {
+                host: host.name.clone(),
+                token: Arc::downgrade(reservation),
+            },
+        );
+        Ok(host)
+    }
+
+    /// The host that can run `language` with the lowest `load` per weight.
+    fn pick_host(&self, language: &Language, load: impl Fn(&Host) -> usize) -> Result<Arc<Host>> {
+        self.hosts
+            .iter()
+            .filter(|host| host.can_run(language))
+            .map(|host| (host, load(host)))
+            // Compares (load + 1) / weight without dividing.
+            .min_by(|(a, a_load), (b, b_load)| {
+                ((a_load + 1) * b.weight).cmp(&((b_load + 1) * a.weight))
+            })
+            .map(|(host, _)| host.clone())
+            .ok_or_else(|| {
+                BotError::Config(format!("No Docker host can run {}", language.name)).into()
+            })
+    }
+
+    async fn take_container(&self, language: Language, host: &Host) -> Result<Container> {
+        let mut found = None;
+        loop {
+            let candidate = {
+                let mut pool = self.containers.lock().await;
+                let candidate = pool
+                    .iter()
+                    .position(|container| {
+                        container.host == host.name
+                            && Self::is_for_image(container, &language.image)
+                    })
+                    .map(|i| pool.remove(i));
+                // Marked as running before it leaves the pool, so that the
+                // reaper always finds it in one of them.
+                if let Some(candidate) = &candidate {
+                    self.running.lock().await.insert(
+                        candidate.id.clone(),
+                        RunningContainer {
+                            host: host.name.clone(),
+                            token: Arc::downgrade(&candidate.token),
+                        },
+                    );
+                }
+                candidate
+            };
+            let Some(candidate) = candidate else {
+                break;
+            };
+
+            if candidate.created_at.elapsed() > self.max_age {
+                println!("Recycling expired container {}", candidate.id);
+                self.running.lock().await.remove(&candidate.id);
+                Self::discard(candidate);
+            } else if !candidate.is_healthy().await {
+                println!("Discarding unhealthy container {}", candidate.id);
+                self.running.lock().await.remove(&candidate.id);
+                Self::discard(candidate);
             } else {
-                false
+                found = Some(candidate);
+                break;
             }
-        }) {
+        }
+
+        let pool = self.clone();
+        let warm_language = language.clone();
+        tokio::spawn(async move {
+            pool.warm(&warm_language).await;
+        });
+
+        if let Some(container) = found {
             println!("Using container from pool");
-            let container_pool = self.containers.clone();
-            tokio::spawn(async move {
-                let container = Container::from_language(language).await;
-                container_pool.lock().await.push(container);
-                println!("Added contaienr to pool");
+            Ok(container)
+        } else {
+            Container::from_language(host, language, &self.instance_id).await
+        }
+    }
+
+    /// Removes a container that is no longer wanted in the background.
+    fn discard(container: Container) {
+        tokio::spawn(async move {
+            if let Err(e) = container.stop().await {
+                log::error!("Failed to remove container {}: {}", container.id, e);
+            }
+        });
+    }
+
+    /// Removes pooled containers older than the maximum age so that image
+    /// updates and daemon restarts are picked up by fresh containers.
+    pub async fn recycle_expired(&self) {
+        let expired = {
+            let mut pool = self.containers.lock().await;
+            let mut expired = vec![];
+            pool.retain(|container| {
+                if container.created_at.elapsed() > self.max_age {
+                    expired.push(container.clone());
+                    false
+                } else {
+                    true
+                }
             });
-            let container = pool[i].clone();
-            pool.remove(i);
-            container
+            expired
+        };
+
+        for container in expired {
+            println!("Recycling expired container {}", container.id);
+            if let Err(e) = container.stop().await {
+                log::error!("Failed to remove container {}: {}", container.id, e);
+            }
+        }
+    }
+
+    fn is_for_image(container: &Container, image: &str) -> bool {
+        if let Some(l) = &container.language {
+            l.image == image
         } else {
-            Container::from_language(language).await
+            false
         }
     }
 
-    pub async fn add_container(&mut self, language: Language) {
-        println!("Adding container to pool... {}", language.image);
-        let container = Container::from_language(language).await;
+    async fn record_demand(&self, language: &Language) {
+        let now = Instant::now();
+        let mut demand = self.demand.lock().await;
+        let requests = demand.entry(language.image.clone()).or_default();
+        requests.push_back(now);
+        while requests
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.demand_window)
+        {
+            requests.pop_front();
+        }
+    }
+
+    /// Number of idle containers to keep for `language`: the number of
+    /// requests seen within the demand window, clamped to the language's
+    /// `pool_min`/`pool_max`.
+    pub async fn target_size(&self, language: &Language) -> usize {
+        let now = Instant::now();
+        let recent = self
+            .demand
+            .lock()
+            .await
+            .get(&language.image)
+            .map_or(0, |requests| {
+                requests
+                    .iter()
+                    .filter(|t| now.duration_since(**t) <= self.demand_window)
+                    .count()
+            });
+
+        recent.clamp(language.pool_min, language.pool_max.max(language.pool_min))
+    }
+
+    async fn pooled_count(&self, image: &str) -> usize {
+        self.containers
+            .lock()
+            .await
+            .iter()
+            .filter(|container| Self::is_for_image(container, image))
+            .count()
+    }
+
+    /// Creates containers for `language` until its pool reaches the target size.
+    pub async fn warm(&self, language: &Language) {
+        let target = self.target_size(language).await;
+        let pooled = self.pooled_count(&language.image).await;
+        let missing = {
+            let mut warming = self.warming.lock().await;
+            let warming = warming.entry(language.image.clone()).or_default();
+            let missing = target.saturating_sub(pooled + *warming);
+            *warming += missing;
+            missing
+        };
+
+        for i in 0..missing {
+            let result = self.add_container(language.clone()).await;
+            if let Some(warming) = self.warming.lock().await.get_mut(&language.image) {
+                *warming = warming.saturating_sub(if result.is_ok() { 1 } else { missing - i });
+            }
+            if let Err(e) = result {
+                log::error!("Failed to warm pool for {}: {}", language.image, e);
+                break;
+            }
+        }
+    }
+
+    /// Spawns a background task that warms the pools of all `languages`,
+    /// working on at most `concurrency` languages at a time.
+    pub fn spawn_warmup(&self, languages: Vec<Language>, concurrency: usize) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            stream::iter(languages)
+                .for_each_concurrent(concurrency.max(1), |language| {
+                    let pool = pool.clone();
+                    async move { pool.warm(&language).await }
+                })
+                .await;
+            println!("Pool warm-up finished");
+        })
+    }
+
+    /// Grows or shrinks every language's pool towards its target size.
+    pub async fn rebalance(&self, languages: &[Language]) {
+        self.recycle_expired().await;
+
+        for language in languages {
+            let target = self.target_size(language).await;
+            let excess = {
+                let mut pool = self.containers.lock().await;
+                let pooled = pool
+                    .iter()
+                    .filter(|container| Self::is_for_image(container, &language.image))
+                    .count();
+                let mut excess = pooled.saturating_sub(target);
+                let mut removed = vec![];
+                pool.retain(|container| {
+                    if excess > 0 && Self::is_for_image(container, &language.image) {
+                        excess -= 1;
+                        removed.push(container.clone());
+                        false
+                    } else {
+                        true
+                    }
+                });
+                removed
+            };
+
+            for container in excess {
+                println!("Shrinking pool for {}", language.image);
+                if let Err(e) = container.stop().await {
+                    log::error!("Failed to remove container {}: {}", container.id, e);
+                }
+            }
+
+            self.warm(language).await;
+        }
+    }
+
+    /// Spawns a background task that periodically rebalances the pools.
+    pub fn spawn_rebalancer(&self, languages: Vec<Language>, interval: Duration) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            // Skip the immediate first tick so the startup warm-up runs alone.
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                interval.tick().await;
+                pool.rebalance(&languages).await;
+            }
+        })
+    }
+
+    /// Creates an idle container for `language` on the host with the fewest
+    /// pooled containers relative to its weight.
+    pub async fn add_container(&self, language: Language) -> Result<()> {
+        let host = {
+            let pool = self.containers.lock().await;
+            self.pick_host(&language, |host| {
+                pool.iter()
+                    .filter(|container| {
+                        container.host == host.name
+                            && Self::is_for_image(container, &language.image)
+                    })
+                    .count()
+            })?
+        };
+        println!(
+            "Adding container to pool... {} on {}",
+            language.image, host.name
+        );
+        let container = Container::from_language(&host, language, &self.instance_id).await?;
         self.containers.lock().await.push(container);
+        Ok(())
     }
 
-    pub async fn cleanup(&mut self) {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-
-        let containers = docker_ps().await;
-        println!("{}", containers.len());
-        for container in &containers {
-            if container
-                .names
-                .clone()
-                .unwrap_or_default()
-                .iter()
-                .find(|name| name.starts_with("/dockerbot-"))
-                .is_some()
-            {
-                println!("Stopping {}", container.id.clone().unwrap());
-                let id = container.id.clone().unwrap();
-                docker
-                    .stop_container(&id, Some(StopContainerOptions { t: 5 }))
-                    .await
-                    .unwrap();
-                docker
-                    .remove_container(
-                        &id,
-                        Some(RemoveContainerOptions {
-                            force: true,
-                            ..Default::default()
-                        }),
-                    )
-                    .await
-                    .unwrap();
+    /// Removes every container left behind by a previous run of this instance.
+    ///
+    /// Containers are matched by the instance label, so other bot instances
+    /// sharing the same Docker daemon are left alone.
+    pub async fn cleanup(&self) {
+        for host in &self.hosts {
+            let containers = match host.runtime.list(&instance_label(&self.instance_id)).await {
+                Ok(containers) => containers,
+                Err(e) => {
+                    log::error!("Failed to list containers on {}: {}", host.name, e);
+                    continue;
+                }
+            };
+            println!("{}: {}", host.name, containers.len());
+            for container in &containers {
+                let Some(id) = container.id.clone() else {
+                    continue;
+                };
+
+                println!("Removing {}", id);
+                if let Err(e) = host.runtime.remove(&id).await {
+                    log::error!("Failed to remove container {}: {}", id, e);
+                }
             }
         }
     }
+
+    /// Spawns a background task that periodically removes containers of this
+    /// instance that are older than `ttl` and neither pooled nor in use.
+    pub fn spawn_reaper(&self, ttl: Duration, interval: Duration) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                pool.reap(ttl).await;
+            }
+        })
+    }
+
+    async fn reap(&self, ttl: Duration) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let pooled: Vec<String> = self
+            .containers
+            .lock()
+            .await
+            .iter()
+            .map(|container| container.id.clone())
+            .collect();
+
+        let running: Vec<String> = {
+            let mut running = self.running.lock().await;
+            running.retain(|_, container| container.token.strong_count() > 0);
+            running.keys().cloned().collect()
+        };
+
+        for host in &self.hosts {
+            self.reap_host(host, ttl, now, &pooled, &running).await;
+        }
+    }
+
+    async fn reap_host(
+        &self,
+        host: &Host,
+        ttl: Duration,
+        now: u64,
+        pooled: &[String],
+        running: &[String],
+    ) {
+        let containers = match host.runtime.list(&instance_label(&self.instance_id)).await {
+            Ok(containers) => containers,
+            Err(e) => {
+                log::error!("Failed to list containers on {}: {}", host.name, e);
+                return;
+            }
+        };
+
+        for container in containers {
+            let Some(id) = container.id else {
+                continue;
+            };
+            if pooled.contains(&id) || running.contains(&id) {
+                continue;
+            }
+
+            let created = container
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get(LABEL_CREATED))
+                .and_then(|created| created.parse::<u64>().ok())
+                .or(container.created.map(|created| created as u64))
+                .unwrap_or_default();
+            if now.saturating_sub(created) < ttl.as_secs() {
+                continue;
+            }
+
+            println!("Reaping stale container {} on {}", id, host.name);
+            if let Err(e) = host.runtime.remove(&id).await {
+                log::error!("Failed to reap container {}: {}", id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bollard::service::ContainerStateStatusEnum;
+
+    use super::*;
+    use crate::{
+        docker::LABEL_INSTANCE,
+        runtime::fake::{self, FakeRuntime},
+    };
+
+    fn pool(runtime: &Arc<FakeRuntime>) -> ContainerPool {
+        ContainerPool::new(
+            vec![fake::with_ready_image(fake::host(runtime), "text")],
+            "test".to_string(),
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        )
+    }
+
+    fn language() -> Language {
+        fake::language("text", "cat {file}", None)
+    }
+
+    /// Lets background removals spawned by the pool run.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn reuses_pooled_containers() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let pool = pool(&runtime);
+        pool.add_container(language()).await.unwrap();
+        let pooled = pool.containers.lock().await[0].id.clone();
+
+        let lease = pool.get_container(language()).await.unwrap();
+        assert_eq!(lease.id, pooled);
+        assert!(pool.containers.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn discards_unhealthy_pooled_containers() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let pool = pool(&runtime);
+        pool.add_container(language()).await.unwrap();
+        let pooled = pool.containers.lock().await[0].id.clone();
+        runtime.set_status(&pooled, ContainerStateStatusEnum::EXITED);
+
+        let lease = pool.get_container(language()).await.unwrap();
+        assert_ne!(lease.id, pooled);
+        settle().await;
+        assert!(!runtime.exists(&pooled));
+    }
+
+    #[tokio::test]
+    async fn removes_containers_when_lease_ends() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let pool = pool(&runtime);
+
+        let lease = pool.get_container(language()).await.unwrap();
+        let id = lease.id.clone();
+        lease.release().await;
+        assert!(!runtime.exists(&id));
+
+        let lease = pool.get_container(language()).await.unwrap();
+        let id = lease.id.clone();
+        drop(lease);
+        settle().await;
+        assert!(!runtime.exists(&id));
+    }
+
+    #[tokio::test]
+    async fn fails_when_image_is_missing() {
+        let runtime = Arc::new(FakeRuntime::new().with_missing_image("text"));
+        let pool = pool(&runtime);
+
+        assert!(pool.get_container(language()).await.is_err());
+        assert!(pool.add_container(language()).await.is_err());
+        pool.warm(&Language {
+            pool_min: 2,
+            ..language()
+        })
+        .await;
+        assert_eq!(pool.warming.lock().await["text"], 0);
+    }
+
+    #[tokio::test]
+    async fn sizes_pools_by_demand() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let pool = pool(&runtime);
+        let language = Language {
+            pool_min: 1,
+            pool_max: 3,
+            ..language()
+        };
+
+        assert_eq!(pool.target_size(&language).await, 1);
+        for _ in 0..5 {
+            pool.record_demand(&language).await;
+        }
+        assert_eq!(pool.target_size(&language).await, 3);
+
+        pool.warm(&language).await;
+        assert_eq!(pool.pooled_count("text").await, 3);
+        pool.demand.lock().await.clear();
+        pool.rebalance(&[language]).await;
+        assert_eq!(pool.pooled_count("text").await, 1);
+        assert_eq!(runtime.container_ids().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn spreads_jobs_over_hosts_by_weight() {
+        let big = Arc::new(FakeRuntime::new());
+        let small = Arc::new(FakeRuntime::new());
+        let pool = ContainerPool::new(
+            vec![
+                fake::with_ready_image(
+                    Arc::new(Host::new("big".to_string(), big.clone(), 2, vec![])),
+                    "text",
+                ),
+                fake::with_ready_image(
+                    Arc::new(Host::new(
+                        "small".to_string(),
+                        small.clone(),
+                        1,
+                        vec!["gpu".to_string()],
+                    )),
+                    "text",
+                ),
+            ],
+            "test".to_string(),
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        );
+
+        let mut leases = vec![];
+        for _ in 0..3 {
+            leases.push(pool.get_container(language()).await.unwrap());
+        }
+        assert_eq!(big.container_ids().len(), 2);
+        assert_eq!(small.container_ids().len(), 1);
+
+        let gpu = Language {
+            host_labels: vec!["gpu".to_string()],
+            ..language()
+        };
+        leases.push(pool.get_container(gpu).await.unwrap());
+        assert_eq!(small.container_ids().len(), 2);
+
+        let arm = Language {
+            host_labels: vec!["arm".to_string()],
+            ..language()
+        };
+        let error = pool.get_container(arm).await.err().unwrap();
+        assert!(matches!(BotError::from(error), BotError::Config(_)));
+    }
+
+    #[tokio::test]
+    async fn spreads_concurrent_jobs() {
+        let first = Arc::new(FakeRuntime::new());
+        let second = Arc::new(FakeRuntime::new());
+        let pool = ContainerPool::new(
+            vec![
+                fake::with_ready_image(
+                    Arc::new(Host::new("first".to_string(), first.clone(), 1, vec![])),
+                    "text",
+                ),
+                fake::with_ready_image(
+                    Arc::new(Host::new("second".to_string(), second.clone(), 1, vec![])),
+                    "text",
+                ),
+            ],
+            "test".to_string(),
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        );
+
+        let leases =
+            futures_util::future::join_all((0..4).map(|_| pool.get_container(language()))).await;
+        assert!(leases.iter().all(|lease| lease.is_ok()));
+        assert_eq!(first.container_ids().len(), 2);
+        assert_eq!(second.container_ids().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn cleanup_only_removes_own_containers() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let pool = pool(&runtime);
+        let own = runtime.insert(HashMap::from([(
+            LABEL_INSTANCE.to_string(),
+            "test".to_string(),
+        )]));
+        let other = runtime.insert(HashMap::from([(
+            LABEL_INSTANCE.to_string(),
+            "other".to_string(),
+        )]));
+
+        pool.cleanup().await;
+        assert!(!runtime.exists(&own));
+        assert!(runtime.exists(&other));
+    }
+
+    #[tokio::test]
+    async fn reaps_only_stale_unused_containers() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let pool = pool(&runtime);
+        let stale = runtime.insert(HashMap::from([
+            (LABEL_INSTANCE.to_string(), "test".to_string()),
+            (LABEL_CREATED.to_string(), "0".to_string()),
+        ]));
+        pool.add_container(language()).await.unwrap();
+        pool.add_container(language()).await.unwrap();
+        let lease = pool.get_container(language()).await.unwrap();
+        // Pooled and leased containers are stale too but must survive.
+        let ttl = Duration::ZERO;
+
+        pool.reap(ttl).await;
+        assert!(!runtime.exists(&stale));
+        assert!(runtime.exists(&lease.id));
+        assert_eq!(runtime.container_ids().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn skips_hosts_without_ready_image() {
+        let pending = Arc::new(FakeRuntime::new());
+        let ready = Arc::new(FakeRuntime::new());
+        let pool = ContainerPool::new(
+            vec![
+                Arc::new(Host::new("pending".to_string(), pending.clone(), 4, vec![])),
+                fake::with_ready_image(
+                    Arc::new(Host::new("ready".to_string(), ready.clone(), 1, vec![])),
+                    "text",
+                ),
+            ],
+            "test".to_string(),
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        );
+
+        let _leases = [
+            pool.get_container(language()).await.unwrap(),
+            pool.get_container(language()).await.unwrap(),
+        ];
+        assert!(pending.container_ids().is_empty());
+        assert_eq!(ready.container_ids().len(), 2);
+
+        // No host has checked this image yet.
+        let other = fake::language("other", "cat {file}", None);
+        let error = pool.get_container(other).await.err().unwrap();
+        assert!(matches!(BotError::from(error), BotError::Config(_)));
+    }
 }
@@ -1,189 +1,153 @@
 use std::{
+    collections::HashMap,
     io::Read,
-    sync::mpsc::{self, Receiver, Sender},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Context, Result};
-use bollard::{
-    container::{
-        CreateContainerOptions, DownloadFromContainerOptions, ListContainersOptions, LogOutput,
-        RemoveContainerOptions, UploadToContainerOptions,
-    },
-    exec::{CreateExecOptions, StartExecResults},
-    service::ContainerSummary,
-    Docker,
-};
+use bollard::service::ContainerStateStatusEnum;
 use flate2::{write::GzEncoder, Compression};
-use futures_util::StreamExt;
 use tar::{Archive, Header};
-use tokio::task::JoinHandle;
-
-use crate::language::Language;
-
-#[allow(unused)]
-pub async fn docker_ps() -> Vec<ContainerSummary> {
-    let docker = Docker::connect_with_local_defaults().unwrap();
 
-    let options = ListContainersOptions::<String> {
-        all: true,
-        ..Default::default()
-    };
+use crate::{
+    error::BotError,
+    hosts::Host,
+    language::Language,
+    runtime::{ContainerRuntime, ExecOptions, LogStream},
+};
 
-    let list = docker.list_containers(Some(options)).await;
+/// Label holding the id of the bot instance that owns a container.
+pub const LABEL_INSTANCE: &str = "dockerbot.instance";
+/// Label holding the name of the language a container was created for.
+pub const LABEL_LANGUAGE: &str = "dockerbot.language";
+/// Label holding the container creation time as unix seconds.
+pub const LABEL_CREATED: &str = "dockerbot.created";
 
-    list.unwrap()
+/// Label filter matching all containers of the given bot instance.
+pub fn instance_label(instance_id: &str) -> String {
+    format!("{}={}", LABEL_INSTANCE, instance_id)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Container {
+    pub runtime: Arc<dyn ContainerRuntime>,
+    /// Name of the host the container runs on.
+    pub host: String,
     pub id: String,
     pub name: String,
     pub language: Option<Language>,
+    /// Shared by every clone of this container. The pool keeps a weak
+    /// reference to it, so a container counts as in use until the last
+    /// handle is dropped, even if the handler unwinds early.
+    pub token: Arc<()>,
+    pub created_at: Instant,
 }
 
 impl Container {
-    pub async fn from_language(language: Language) -> Self {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-        let config = language.get_container_option();
-        let name = format!("dockerbot-{}", uuid::Uuid::new_v4().to_string());
-
-        let id = docker
-            .create_container(
-                Some(CreateContainerOptions {
-                    name: name.clone(),
-                    platform: None,
-                }),
-                config,
-            )
-            .await
-            .unwrap()
-            .id;
-
-        Self {
+    pub async fn from_language(host: &Host, language: Language, instance_id: &str) -> Result<Self> {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let labels = HashMap::from([
+            (LABEL_INSTANCE.to_string(), instance_id.to_string()),
+            (LABEL_LANGUAGE.to_string(), language.name.clone()),
+            (LABEL_CREATED.to_string(), created.to_string()),
+        ]);
+        let sandbox = language.sandbox.or(&host.sandbox);
+        let config = language
+            .get_container_option(labels, &sandbox)
+            .map_err(|e| BotError::Config(format!("{:#}", e)))?;
+        let name = format!("dockerbot-{}", uuid::Uuid::new_v4());
+
+        let id = host.runtime.create(&name, config).await?;
+
+        Ok(Self {
+            runtime: host.runtime.clone(),
+            host: host.name.clone(),
             id,
             name,
             language: Some(language),
-        }
+            token: Arc::new(()),
+            created_at: Instant::now(),
+        })
     }
 
-    pub async fn stop(&self) {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-
-        docker
-            .remove_container(
-                &self.id,
-                Some(RemoveContainerOptions {
-                    force: true,
-                    ..Default::default()
-                }),
-            )
-            .await
-            .unwrap();
+    /// CPU time consumed by the container since it was started.
+    pub async fn cpu_usage(&self) -> Result<Duration> {
+        self.runtime.cpu_usage(&self.id).await
     }
 
-    pub async fn run_code(&self) -> (JoinHandle<()>, Receiver<Option<LogOutput>>, Sender<()>) {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-        let language = self.language.clone().unwrap();
+    /// Checks that the container still exists and has not exited.
+    pub async fn is_healthy(&self) -> bool {
+        matches!(
+            self.runtime.status(&self.id).await,
+            Ok(Some(
+                ContainerStateStatusEnum::CREATED | ContainerStateStatusEnum::RUNNING
+            ))
+        )
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        self.runtime.remove(&self.id).await
+    }
+
+    fn language(&self) -> Result<&Language> {
+        self.language
+            .as_ref()
+            .with_context(|| format!("Container {} has no language", self.id))
+    }
+
+    fn exec_command(command: &str) -> Vec<String> {
+        command.split(' ').map(String::from).collect()
+    }
+
+    /// Starts the program. Dropping the returned stream stops reading its
+    /// output; the process itself ends when the container is removed.
+    ///
+    /// Programs given `stdin` never get a terminal: the daemon closes the
+    /// output of a terminal as soon as its input is closed, which would cut
+    /// the output short. Colors are still requested through the environment.
+    pub async fn run_code(&self, color: bool, stdin: Option<Vec<u8>>) -> Result<LogStream> {
+        let language = self.language()?;
         let file_name = format!("{}.{}", self.name, language.extension);
+        let cmd = Self::exec_command(&language.get_run_command(file_name));
+        let options = ExecOptions {
+            tty: color && stdin.is_none(),
+            env: language.get_exec_env(color),
+            stdin,
+        };
 
-        let exec = docker
-            .create_exec(
-                &self.id,
-                CreateExecOptions {
-                    attach_stdout: Some(true),
-                    attach_stdin: Some(true),
-                    attach_stderr: Some(true),
-                    cmd: Some(language.get_run_command(file_name).split(" ").collect()),
-                    ..Default::default()
-                },
-            )
+        self.runtime
+            .exec(&self.id, cmd, options)
             .await
-            .unwrap()
-            .id;
-
-        let (tx, rx) = mpsc::channel();
-        let (end_tx, end_rx) = mpsc::channel::<()>();
-
-        let handle = tokio::spawn(async move {
-            if let StartExecResults::Attached { mut output, .. } =
-                docker.start_exec(&exec, None).await.unwrap()
-            {
-                let mut end_flag = false;
-                while !end_flag {
-                    if let Ok(_) = end_rx.recv_timeout(Duration::from_millis(10)) {
-                        break;
-                    }
-                    if let Ok(res) =
-                        tokio::time::timeout(Duration::from_millis(100), output.next()).await
-                    {
-                        if let Some(Ok(msg)) = res {
-                            tx.send(Some(msg)).unwrap();
-                        } else {
-                            end_flag = true;
-                        }
-                    }
-                }
-            } else {
-                unreachable!();
-            }
-
-            tx.send(None).unwrap();
-        });
-
-        (handle, rx, end_tx)
+            .with_context(|| format!("Failed to run code in {}", self.id))
     }
 
-    pub async fn compile(&self) -> Option<(JoinHandle<()>, Receiver<Option<LogOutput>>)> {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-        let language = self.language.clone().unwrap();
+    /// Starts the compiler, if the language has one.
+    pub async fn compile(&self, color: bool) -> Result<Option<LogStream>> {
+        let language = self.language()?;
         let file_name = format!("{}.{}", self.name, language.extension);
+        let Some(compile) = language.get_compile_command(file_name) else {
+            return Ok(None);
+        };
+        let options = ExecOptions {
+            tty: color,
+            env: language.get_exec_env(color),
+            ..Default::default()
+        };
 
-        if let Some(compile) = language.get_compile_command(file_name.clone()) {
-            let exec = docker
-                .create_exec(
-                    &self.id,
-                    CreateExecOptions {
-                        attach_stdout: Some(true),
-                        attach_stdin: Some(true),
-                        attach_stderr: Some(true),
-                        cmd: Some(compile.split(" ").collect()),
-                        ..Default::default()
-                    },
-                )
-                .await
-                .unwrap()
-                .id;
-
-            let (tx, rx) = mpsc::channel();
-
-            let handle = tokio::spawn(async move {
-                if let StartExecResults::Attached { mut output, .. } =
-                    docker.start_exec(&exec, None).await.unwrap()
-                {
-                    while let Some(Ok(msg)) = output.next().await {
-                        tx.send(Some(msg)).unwrap();
-                    }
-                } else {
-                    unreachable!();
-                }
-
-                tx.send(None).unwrap();
-            });
-
-            return Some((handle, rx));
-        }
-        return None;
+        let output = self
+            .runtime
+            .exec(&self.id, Self::exec_command(&compile), options)
+            .await
+            .with_context(|| format!("Failed to compile code in {}", self.id))?;
+        Ok(Some(output))
     }
 
     pub async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-        let options = Some(DownloadFromContainerOptions { path });
-        let mut download = docker.download_from_container(&self.id, options);
-        let mut result: Vec<u8> = vec![];
-        while let Some(Ok(d)) = download.next().await {
-            result.append(&mut d.into());
-        }
+        let result = self.runtime.download(&self.id, path).await?;
 
         Self::extract(result)
     }
@@ -201,84 +165,205 @@ impl Container {
         Ok(result)
     }
 
-    pub async fn upload_file(&self, data: Vec<u8>, path: &str) {
-        let docker = Docker::connect_with_local_defaults().unwrap();
+    /// Packs `data` into a gzip compressed tar archive at `path`.
+    fn archive(path: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let encoder = GzEncoder::new(vec![], Compression::default());
+        let mut tar = tar::Builder::new(encoder);
+
+        let mut header = Header::new_gnu();
+        header.set_path(path)?;
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+
+        tar.append(&header, data)?;
+        Ok(tar.into_inner()?.finish()?)
+    }
+
+    pub async fn upload_file(&self, data: Vec<u8>, path: &str) -> Result<()> {
+        let archive =
+            Self::archive(path, &data).with_context(|| format!("Failed to pack {}", path))?;
 
-        let archive = {
-            let encoder = GzEncoder::new(vec![], Compression::default());
-            let mut tar = tar::Builder::new(encoder);
+        self.runtime.start(&self.id).await?;
+        self.runtime
+            .upload(&self.id, archive)
+            .await
+            .with_context(|| format!("Failed to upload {} to {}", path, self.id))
+    }
 
-            let mut header = Header::new_gnu();
-            header.set_path(path).unwrap();
-            header.set_size(data.len() as u64);
-            header.set_cksum();
+    pub async fn upload_source_file(&self, content: &str, file_name: String) -> Result<()> {
+        let path = self.language()?.get_path(file_name);
+        self.upload_file(content.as_bytes().to_vec(), &path).await
+    }
+}
 
-            tar.append(&header, data.as_slice()).unwrap();
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
 
-            tar.finish().unwrap();
+    use super::*;
+    use crate::{
+        config::SandboxConfig,
+        runtime::fake::{self, FakeRuntime},
+    };
+
+    async fn collect(output: LogStream) -> String {
+        output
+            .map(|output| output.unwrap().to_string())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn labels_containers_with_instance_and_language() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let language = fake::language("text", "cat {file}", None);
+        let container = Container::from_language(&fake::host(&runtime), language, "test")
+            .await
+            .unwrap();
+
+        let labels = runtime.labels(&container.id).unwrap();
+        assert_eq!(labels[LABEL_INSTANCE], "test");
+        assert_eq!(labels[LABEL_LANGUAGE], "text");
+        assert!(labels.contains_key(LABEL_CREATED));
+    }
 
-            let encoder = tar.into_inner().unwrap();
-            encoder.finish().unwrap()
+    #[tokio::test]
+    async fn applies_sandbox_options_with_language_overrides() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let mut host = Host::new("test".to_string(), runtime.clone(), 1, vec![]);
+        host.sandbox = SandboxConfig {
+            runtime: Some("runsc".to_string()),
+            apparmor_profile: Some("docker-bot".to_string()),
+            ..Default::default()
         };
+        let language = Language {
+            sandbox: SandboxConfig {
+                runtime: Some("kata-runtime".to_string()),
+                ..Default::default()
+            },
+            ..fake::language("text", "cat {file}", None)
+        };
+        let container = Container::from_language(&host, language, "test")
+            .await
+            .unwrap();
 
-        {
-            docker
-                .start_container::<String>(&self.id, None)
-                .await
-                .unwrap();
-
-            docker
-                .upload_to_container(
-                    &self.id,
-                    Some(UploadToContainerOptions {
-                        path: "/",
-                        ..Default::default()
-                    }),
-                    archive.into(),
-                )
-                .await
-                .unwrap();
-        }
+        let host_config = runtime.host_config(&container.id).unwrap();
+        assert_eq!(host_config.runtime.as_deref(), Some("kata-runtime"));
+        assert_eq!(
+            host_config.security_opt,
+            Some(vec!["apparmor=docker-bot".to_string()])
+        );
     }
 
-    pub async fn upload_source_file(&self, content: &str, file_name: String) {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-        let path = self.language.clone().unwrap().get_path(file_name.clone());
+    #[tokio::test]
+    async fn runs_uploaded_source() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let language = fake::language("text", "cat ./{file}", None);
+        let container = Container::from_language(&fake::host(&runtime), language, "test")
+            .await
+            .unwrap();
+        let file_name = format!("{}.txt", container.name);
+        container
+            .upload_source_file("hello\n", file_name.clone())
+            .await
+            .unwrap();
+        assert_eq!(runtime.file(&container.id, &file_name).unwrap(), b"hello\n");
 
-        let code = {
-            let encoder = GzEncoder::new(vec![], Compression::default());
-            let mut tar = tar::Builder::new(encoder);
+        assert!(container.compile(false).await.unwrap().is_none());
+        let output = container.run_code(false, None).await.unwrap();
+        assert_eq!(collect(output).await, "hello\n");
+    }
 
-            let mut header = Header::new_gnu();
-            header.set_path(&path).unwrap();
-            header.set_size(content.as_bytes().len() as u64);
-            header.set_cksum();
+    #[tokio::test]
+    async fn compiles_before_running() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let language = fake::language("text", "cat out", Some("cp {file} out"));
+        let container = Container::from_language(&fake::host(&runtime), language, "test")
+            .await
+            .unwrap();
+        container
+            .upload_source_file("compiled", format!("{}.txt", container.name))
+            .await
+            .unwrap();
 
-            tar.append(&header, content.as_bytes()).unwrap();
+        let output = container.compile(true).await.unwrap().unwrap();
+        assert_eq!(collect(output).await, "");
+        let output = container.run_code(true, None).await.unwrap();
+        assert_eq!(collect(output).await, "compiled");
 
-            tar.finish().unwrap();
+        let (_, cmd, options) = runtime.execs().pop().unwrap();
+        assert_eq!(cmd, ["cat", "out"]);
+        assert!(options.tty);
+        assert!(options.env.unwrap().contains(&"FORCE_COLOR=1".to_string()));
+    }
 
-            let encoder = tar.into_inner().unwrap();
-            encoder.finish().unwrap()
-        };
+    #[tokio::test]
+    async fn runs_without_terminal_when_given_stdin() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let language = fake::language("text", "cat", None);
+        let container = Container::from_language(&fake::host(&runtime), language, "test")
+            .await
+            .unwrap();
+        container
+            .upload_source_file("", format!("{}.txt", container.name))
+            .await
+            .unwrap();
+
+        let output = container
+            .run_code(true, Some(b"input".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(collect(output).await, "input");
+
+        let (_, _, options) = runtime.execs().pop().unwrap();
+        assert!(!options.tty);
+        assert!(options.env.unwrap().contains(&"FORCE_COLOR=1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reports_failed_execs() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let language = fake::language("text", "cat {file}", None);
+        let container = Container::from_language(&fake::host(&runtime), language, "test")
+            .await
+            .unwrap();
+        container.stop().await.unwrap();
+
+        let error = container.run_code(false, None).await.err().unwrap();
+        assert!(error.to_string().starts_with("Failed to run code"));
+    }
+
+    #[tokio::test]
+    async fn downloads_uploaded_files() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let language = fake::language("text", "cat {file}", None);
+        let container = Container::from_language(&fake::host(&runtime), language, "test")
+            .await
+            .unwrap();
+        container
+            .upload_file(b"data".to_vec(), "input.bin")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            container.download_file("/input.bin").await.unwrap(),
+            b"data"
+        );
+        assert!(container.download_file("/missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_exited_containers_as_unhealthy() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let language = fake::language("text", "cat {file}", None);
+        let container = Container::from_language(&fake::host(&runtime), language, "test")
+            .await
+            .unwrap();
+        assert!(container.is_healthy().await);
 
-        {
-            docker
-                .start_container::<String>(&self.id, None)
-                .await
-                .unwrap();
-
-            docker
-                .upload_to_container(
-                    &self.id,
-                    Some(UploadToContainerOptions {
-                        path: "/",
-                        ..Default::default()
-                    }),
-                    code.into(),
-                )
-                .await
-                .unwrap();
-        }
+        runtime.set_status(&container.id, ContainerStateStatusEnum::EXITED);
+        assert!(!container.is_healthy().await);
+        container.stop().await.unwrap();
+        assert!(!container.is_healthy().await);
     }
 }
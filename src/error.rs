@@ -0,0 +1,76 @@
+//! Errors of the execution pipeline, grouped by who can fix them.
+
+use poise::serenity_prelude as serenity;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BotError {
+    /// The Docker daemon or a container failed.
+    #[error("Docker error: {0:#}")]
+    Docker(anyhow::Error),
+    /// A request to Discord failed.
+    #[error("Discord error: {0}")]
+    Discord(Box<serenity::Error>),
+    /// The configuration cannot work, such as a missing seccomp profile.
+    #[error("Configuration error: {0}")]
+    Config(String),
+    /// The request cannot be served. The message is meant for the user.
+    #[error("{0}")]
+    User(String),
+}
+
+impl BotError {
+    /// Whether the bot is at fault rather than the request.
+    pub fn is_internal(&self) -> bool {
+        !matches!(self, BotError::User(_))
+    }
+
+    /// What to tell the user. Details of internal errors are only logged.
+    pub fn user_message(&self) -> String {
+        match self {
+            BotError::Docker(_) => {
+                "The sandbox failed to run the code, try again later.".to_string()
+            }
+            BotError::Discord(_) => "Discord rejected a request, try again later.".to_string(),
+            BotError::Config(_) => "The bot is misconfigured, please tell its owner.".to_string(),
+            BotError::User(message) => message.clone(),
+        }
+    }
+}
+
+impl From<serenity::Error> for BotError {
+    fn from(e: serenity::Error) -> Self {
+        BotError::Discord(Box::new(e))
+    }
+}
+
+impl From<anyhow::Error> for BotError {
+    /// Keeps the category of a `BotError` passed through `anyhow`, even with
+    /// context added. Anything else comes from Docker.
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast().unwrap_or_else(BotError::Docker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn keeps_categories_through_anyhow() {
+        let config: anyhow::Error = BotError::Config("no profile".to_string()).into();
+        let config = BotError::from(config.context("Failed to create container"));
+        assert!(matches!(config, BotError::Config(_)));
+
+        let docker = BotError::from(anyhow!("connection refused"));
+        assert!(matches!(docker, BotError::Docker(_)));
+        assert!(docker.to_string().contains("connection refused"));
+        assert!(!docker.user_message().contains("connection refused"));
+
+        let user = BotError::User("Rust is disabled.".to_string());
+        assert!(!user.is_internal());
+        assert_eq!(user.user_message(), "Rust is disabled.");
+    }
+}
@@ -1,14 +1,31 @@
-use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use std::time::Duration;
 
 use regex::Regex;
-use tokio::time::{sleep_until, Instant};
 
-use crate::{docker::Container, Data, Error};
+use crate::{
+    ansi,
+    config::{Config, TriggerMode},
+    error::BotError,
+    executor::{Job, JobFile, JobLimits},
+    guild_settings::GuildSettings,
+    image::ImageState,
+    output::{escape_code_block, preview, MESSAGE_LIMIT},
+    results::{
+        delete_button, ExecutionRecord, Source, DELETE_BUTTON, FILE_BUTTON, PAGE_BUTTON_PREFIX,
+        RERUN_BUTTON,
+    },
+    scheduler::TicketUpdate,
+    Data, Error,
+};
+
+use poise::serenity_prelude::{
+    self as serenity, ChannelId, ComponentInteraction, CreateActionRow, CreateAllowedMentions,
+    CreateAttachment, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage,
+    EditMessage, GuildId, Message,
+};
 
-use poise::serenity_prelude::{self as serenity, CreateAttachment, EditMessage, Message};
+/// Characters the "File not found" notes may take before they are collapsed.
+const MISSING_FILES_NOTE_LIMIT: usize = 300;
 
 pub async fn event_handler(
     ctx: &serenity::Context,
@@ -22,7 +39,15 @@ pub async fn event_handler(
         }
 
         serenity::FullEvent::Message { new_message } => {
-            on_message(ctx, data, new_message).await;
+            handle_message(ctx, data, new_message).await;
+        }
+
+        serenity::FullEvent::InteractionCreate { interaction } => {
+            if let Some(component) = interaction.as_message_component() {
+                if let Err(e) = on_component(ctx, data, component).await {
+                    log::error!("Failed to handle interaction {}: {}", component.id, e);
+                }
+            }
         }
 
         _ => {}
@@ -30,128 +55,463 @@ pub async fn event_handler(
     Ok(())
 }
 
-async fn on_message(ctx: &serenity::Context, data: &Data, new_message: &Message) {
-    let regex = Regex::new("^(?P<codeblock>```(?:(?P<language>[^\n]*)\n)?(?P<code>[\\s\\S]+?)\n```)(?:\\s*(?P<paths>(?:(?:/|\\.\\.?/)?(?:[^/\\s]+/)*[^/\\s]+\\s*)+))?$").unwrap();
-
-    let capture = regex.captures(&new_message.content);
+/// Runs the code in `message`, if it has any.
+async fn handle_message(ctx: &serenity::Context, data: &Data, message: &Message) {
+    if let Some(source) = parse_message(ctx, data, message).await {
+        handle_run(ctx, data, source).await;
+    }
+}
 
-    if let Some(captures) = capture {
-        let language = captures.name("language").unwrap().as_str();
-        let code = captures.name("code").unwrap().as_str();
+/// Runs `source` and tells its author when that fails.
+async fn handle_run(ctx: &serenity::Context, data: &Data, source: Source) {
+    let Err(e) = run(ctx, data, source.clone()).await else {
+        return;
+    };
+    if e.is_internal() {
+        log::error!("Failed to run message {}: {}", source.message, e);
+    }
+    if let Err(e) = reply(ctx, &source, e.user_message()).await {
+        log::error!(
+            "Failed to report error for message {}: {}",
+            source.message,
+            e
+        );
+    }
+}
 
-        let config = data.config.lock().await.clone();
+/// Replies to the message of `source` without pinging anyone, whatever the
+/// content is.
+async fn reply(
+    ctx: &serenity::Context,
+    source: &Source,
+    content: impl Into<String>,
+) -> Result<Message, BotError> {
+    reply_with(ctx, source, CreateMessage::new().content(content)).await
+}
 
-        let language = config.get_language(&String::from(language));
+async fn reply_with(
+    ctx: &serenity::Context,
+    source: &Source,
+    builder: CreateMessage,
+) -> Result<Message, BotError> {
+    Ok(source
+        .channel
+        .send_message(
+            &ctx.http,
+            builder
+                .reference_message((source.channel, source.message))
+                .allowed_mentions(CreateAllowedMentions::new()),
+        )
+        .await?)
+}
 
-        if let Some(language) = language {
-            let container = {
-                let mut pool = data.container_pool.lock().await;
-                pool.get_container(language.clone()).await
-            };
-            let file_name = format!("{}.{}", container.name, language.extension.clone());
-
-            let mut message = new_message
-                .reply(&ctx.http, format!("Container created: {}", container.id))
-                .await
-                .unwrap();
-
-            for attachment in &new_message.attachments {
-                let data = attachment.download().await.unwrap();
-                container
-                    .upload_file(data, &format!("{}", attachment.filename))
-                    .await;
-            }
+/// An edit that keeps the message from pinging anyone.
+fn quiet_edit() -> EditMessage {
+    EditMessage::new().allowed_mentions(CreateAllowedMentions::new())
+}
 
-            container.upload_source_file(code, file_name.clone()).await;
+async fn on_component(
+    ctx: &serenity::Context,
+    data: &Data,
+    component: &ComponentInteraction,
+) -> Result<(), BotError> {
+    let custom_id = component.data.custom_id.as_str();
+    let reply_id = component.message.id;
 
-            let compile_buf = Arc::new(Mutex::new(String::default()));
-            let b = Arc::clone(&compile_buf);
+    if let Some(page) = custom_id.strip_prefix(PAGE_BUTTON_PREFIX) {
+        let page = page.parse::<usize>().unwrap_or_default();
+        let response = match data.results.get(reply_id).await {
+            Some(record) if page < record.pages.len() => CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content(record.render_page(page))
+                    .components(record.components(true, Some(page)))
+                    .allowed_mentions(CreateAllowedMentions::new()),
+            ),
+            _ => ephemeral("This result is no longer available."),
+        };
+        component.create_response(&ctx.http, response).await?;
+        return Ok(());
+    }
 
-            if let Some((compile_handle, compile_rx)) = container.compile().await {
-                let rx_handle = tokio::spawn(async move {
-                    while let Ok(Some(msg)) = compile_rx.recv() {
-                        *b.lock().unwrap() += &msg.to_string();
-                    }
-                });
+    if ![RERUN_BUTTON, DELETE_BUTTON, FILE_BUTTON].contains(&custom_id) {
+        return Ok(());
+    }
 
-                let (_, _) = tokio::join!(compile_handle, rx_handle);
-            }
+    let Some(source) = data.results.source(reply_id).await else {
+        component
+            .create_response(&ctx.http, ephemeral("This result is no longer available."))
+            .await?;
+        return Ok(());
+    };
 
-            let (run_handle, run_code_rx, end_tx) = container.run_code().await;
+    let owner = data.config.lock().await.owner;
+    let user = component.user.id;
 
-            let buf = Arc::new(Mutex::new(String::default()));
-            let b = Arc::clone(&buf);
-            let rx_handle = tokio::spawn(async move {
-                while let Ok(Some(msg)) = run_code_rx.recv() {
-                    *b.lock().unwrap() += &msg.to_string();
-                }
-            });
-
-            let timeout = Arc::new(Mutex::new(false));
-            let t = Arc::clone(&timeout);
-            tokio::spawn(async move {
-                sleep_until(Instant::now() + Duration::from_secs(120)).await;
-                if let Ok(_) = end_tx.send(()) {
-                    *t.lock().unwrap() = true;
+    match custom_id {
+        RERUN_BUTTON | DELETE_BUTTON if user != source.author && user.get() != owner => {
+            component
+                .create_response(
+                    &ctx.http,
+                    ephemeral("Only the author of the code can do that."),
+                )
+                .await?;
+        }
+        RERUN_BUTTON => {
+            component
+                .create_response(&ctx.http, CreateInteractionResponse::Acknowledge)
+                .await?;
+            // The same code, under the guild's current settings and limits.
+            let source = Source {
+                guild: component.guild_id,
+                ..source
+            };
+            handle_run(ctx, data, source).await;
+        }
+        DELETE_BUTTON => {
+            component
+                .create_response(&ctx.http, CreateInteractionResponse::Acknowledge)
+                .await?;
+            data.results.remove(reply_id).await;
+            component.message.delete(&ctx.http).await?;
+        }
+        _ => {
+            let response = match data.results.get(reply_id).await {
+                Some(record) => {
+                    let mut message = CreateInteractionResponseMessage::new()
+                        .add_file(CreateAttachment::bytes(
+                            ansi::strip(&record.output),
+                            "result_log.txt",
+                        ))
+                        .allowed_mentions(CreateAllowedMentions::new());
+                    if !record.compile_output.is_empty() {
+                        message = message.add_file(CreateAttachment::bytes(
+                            ansi::strip(&record.compile_output),
+                            "compile_log.txt",
+                        ));
+                    }
+                    CreateInteractionResponse::Message(message)
                 }
-            });
+                None => ephemeral("The code is still running."),
+            };
+            component.create_response(&ctx.http, response).await?;
+        }
+    }
 
-            let (_, _) = tokio::join!(run_handle, rx_handle);
+    Ok(())
+}
 
-            let mut edit_message = EditMessage::new();
-            let mut content = if *timeout.lock().unwrap() {
-                "Timeout".to_string()
-            } else if compile_buf.lock().unwrap().is_empty() {
-                format!(
-                    "Result\n```\n{}\n```",
-                    buf.lock().unwrap().replace("@", "\\@")
-                )
-            } else {
-                format!(
-                    "Result\nCompilation log\n```\n{}\n```\nExecution log\n```{}\n```",
-                    compile_buf.lock().unwrap().replace("@", "\\@"),
-                    buf.lock().unwrap().replace("@", "\\@")
-                )
-            };
+fn ephemeral(content: &str) -> CreateInteractionResponse {
+    CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .content(content)
+            .ephemeral(true),
+    )
+}
 
-            if content.len() >= 1000 {
-                content = "Result log out of length.".to_string();
+/// The code in `new_message` if it should be run, checking the channel
+/// and trigger settings. Messages without code are ignored silently.
+async fn parse_message(
+    ctx: &serenity::Context,
+    data: &Data,
+    new_message: &Message,
+) -> Option<Source> {
+    let regex = Regex::new("^(?P<codeblock>```(?:(?P<language>[^\n]*)\n)?(?P<code>[\\s\\S]+?)\n```)(?:\\s*(?P<paths>(?:(?:/|\\.\\.?/)?(?:[^/\\s]+/)*[^/\\s]+\\s*)+))?$").unwrap();
 
-                edit_message = edit_message.new_attachment(CreateAttachment::bytes(
-                    buf.lock().unwrap().as_bytes(),
-                    "result_log.txt",
-                ));
+    let settings = settings(data, new_message.guild_id).await;
+    let config = data.config.lock().await.clone();
+    if !is_channel_allowed(&config, &settings, new_message.channel_id) {
+        return None;
+    }
 
-                if !((*compile_buf.lock().unwrap()).is_empty()) {
-                    edit_message = edit_message.new_attachment(CreateAttachment::bytes(
-                        compile_buf.lock().unwrap().as_bytes(),
-                        "compile_log.txt",
-                    ));
-                }
-            }
+    let content = new_message.content.as_str();
+    let content = match settings.trigger.unwrap_or(config.trigger) {
+        TriggerMode::Always | TriggerMode::Allowlist => content,
+        TriggerMode::Prefix => content.strip_prefix(&config.prefix)?.trim_start(),
+        TriggerMode::Mention => {
+            let bot = ctx.cache.current_user().id;
+            content
+                .strip_prefix(&format!("<@{}>", bot))
+                .or_else(|| content.strip_prefix(&format!("<@!{}>", bot)))?
+                .trim_start()
+        }
+    };
 
-            let paths: Vec<&str> = if let Some(matc) = captures.name("paths") {
-                matc.as_str()
-            } else {
-                ""
-            }
+    let captures = regex.captures(content)?;
+    let language = captures.name("language").map_or("", |m| m.as_str());
+    config.get_language(&language.to_string())?;
+
+    Some(Source {
+        author: new_message.author.id,
+        roles: new_message
+            .member
+            .as_ref()
+            .map(|member| member.roles.clone())
+            .unwrap_or_default(),
+        guild: new_message.guild_id,
+        channel: new_message.channel_id,
+        message: new_message.id,
+        language: language.to_string(),
+        code: captures.name("code").map_or("", |m| m.as_str()).to_string(),
+        paths: captures
+            .name("paths")
+            .map_or("", |m| m.as_str())
             .lines()
             .filter(|l| !l.is_empty())
-            .collect();
+            .map(String::from)
+            .collect(),
+        attachments: new_message.attachments.clone(),
+    })
+}
+
+async fn settings(data: &Data, guild: Option<GuildId>) -> GuildSettings {
+    match guild {
+        Some(guild) => data.guild_settings.get(guild.get()).await,
+        None => GuildSettings::default(),
+    }
+}
 
-            for path in paths {
-                if let Ok(file) = container.download_file(path).await {
-                    edit_message = edit_message.new_attachment(CreateAttachment::bytes(file, path));
+fn is_channel_allowed(config: &Config, settings: &GuildSettings, channel: ChannelId) -> bool {
+    let channel = channel.get();
+    settings.is_channel_allowed(channel)
+        && (settings.trigger.unwrap_or(config.trigger) != TriggerMode::Allowlist
+            || config.allowed_channels.contains(&channel)
+            || settings.allowed_channels.contains(&channel))
+}
+
+/// Runs `source`, applying the settings and limits of its guild.
+async fn run(ctx: &serenity::Context, data: &Data, source: Source) -> Result<(), BotError> {
+    let settings = settings(data, source.guild).await;
+    let config = data.config.lock().await.clone();
+    if !is_channel_allowed(&config, &settings, source.channel) {
+        return Err(BotError::User(
+            "Code cannot be run in this channel.".to_string(),
+        ));
+    }
+
+    let Some(language) = config.get_language(&source.language) else {
+        return Err(BotError::User(format!(
+            "{} is no longer available.",
+            source.language
+        )));
+    };
+
+    if !settings.is_language_enabled(&language.name) {
+        return Err(BotError::User(format!(
+            "{} is disabled in this server.",
+            language.name
+        )));
+    }
+
+    match data.images.state(&language) {
+        ImageState::Ready => {}
+        ImageState::Pending => {
+            return Err(BotError::User(format!(
+                "{} is still being prepared, try again later.",
+                language.name
+            )));
+        }
+        ImageState::Unavailable => {
+            return Err(BotError::User(format!(
+                "{} is currently unavailable.",
+                language.name
+            )));
+        }
+    }
+
+    let user = source.author.get();
+    let guild = source.guild.map(|guild| guild.get());
+    let roles: Vec<u64> = source.roles.iter().map(|role| role.get()).collect();
+    let limited = user != config.owner && !data.limiter.is_exempt(user, &roles);
+    if limited {
+        if let Err(e) = data.limiter.check(user, guild, &settings.limits) {
+            return Err(BotError::User(e.to_string()));
+        }
+    }
+
+    let mut ticket = data.scheduler.enqueue(user, guild);
+    let mut queued_message: Option<Message> = None;
+    let _permit = loop {
+        match ticket.next().await {
+            TicketUpdate::Admitted(permit) => break permit,
+            TicketUpdate::Queued(position) => {
+                let content = format!("Queued, position {}", position);
+                if let Some(message) = &mut queued_message {
+                    message
+                        .edit(&ctx.http, quiet_edit().content(content))
+                        .await?;
                 } else {
-                    content += &format!("\nFile not found: `{}`", path);
+                    queued_message = Some(reply(ctx, &source, content).await?);
                 }
             }
+        }
+    };
+
+    let mut files = vec![];
+    for attachment in &source.attachments {
+        files.push(JobFile {
+            path: attachment.filename.clone(),
+            data: attachment.download().await?,
+        });
+    }
+
+    let color = language.color.unwrap_or(config.color_output);
+    let job = Job {
+        language: language.clone(),
+        source: source.code.clone(),
+        files,
+        stdin: None,
+        downloads: source.paths.clone(),
+        color,
+        limits: JobLimits {
+            timeout: Duration::from_secs(config.run_timeout),
+            output_bytes: config.max_output_bytes,
+        },
+    };
+    let execution = match data.executor.start(job).await {
+        Ok(execution) => execution,
+        Err(e) => {
+            if let Some(message) = queued_message {
+                let _ = message.delete(&ctx.http).await;
+            }
+            return Err(e);
+        }
+    };
 
-            edit_message = edit_message.content(content);
+    let content = format!("Container created: {}", execution.container_id());
+    let components = vec![CreateActionRow::Buttons(vec![delete_button()])];
+    let mut message = if let Some(mut message) = queued_message {
+        message
+            .edit(
+                &ctx.http,
+                quiet_edit().content(content).components(components),
+            )
+            .await?;
+        message
+    } else {
+        reply_with(
+            ctx,
+            &source,
+            CreateMessage::new().content(content).components(components),
+        )
+        .await?
+    };
 
-            message.edit(&ctx.http, edit_message).await.unwrap();
+    let cancel = data.results.start(message.id, source.clone()).await;
 
-            container.stop().await;
+    let result = match execution.run(cancel, None).await {
+        Ok(result) => result,
+        Err(e) => {
+            // The error is reported in a reply of its own.
+            data.results.finish(message.id).await;
+            let _ = message.delete(&ctx.http).await;
+            return Err(e);
         }
+    };
+
+    if !data.results.finish(message.id).await || result.cancelled {
+        // Deleted through its reply while running.
+        return Ok(());
+    }
+
+    if limited {
+        if let Some(cpu) = result.cpu_time {
+            data.limiter.record_cpu(user, guild, cpu);
+        }
+    }
+
+    let (output, compile_output, block) = if color {
+        (
+            ansi::to_discord(&result.output),
+            ansi::to_discord(&result.compile_output),
+            "ansi",
+        )
+    } else {
+        (result.output, result.compile_output, "")
+    };
+    // Escaping grows the text, so it is measured and cut afterwards.
+    let (escaped_output, escaped_compile_output) = (
+        escape_code_block(&output),
+        escape_code_block(&compile_output),
+    );
+    let mut files = vec![];
+    let mut missing = vec![];
+    for download in result.downloads {
+        match download.data {
+            Some(file) => files.push(CreateAttachment::bytes(file, download.path)),
+            None => missing.push(download.path),
+        }
+    }
+    let mut notes = missing_files_note(&missing);
+    if result.truncated {
+        notes += "\nOutput limit reached, the program was stopped";
+    }
+    // Leave room for the surrounding text and the notes.
+    let budget = MESSAGE_LIMIT - 100 - notes.chars().count();
+
+    let mut edit_message = quiet_edit();
+    let mut long_output = false;
+    let mut content = if result.timed_out {
+        "Timeout".to_string()
+    } else if compile_output.is_empty() {
+        long_output = escaped_output.chars().count() > budget;
+        format!(
+            "Result\n```{}\n{}\n```",
+            block,
+            preview(&escaped_output, budget)
+        )
+    } else {
+        // Each log only gets half of the budget.
+        long_output = escaped_output.chars().count() > budget / 2
+            || escaped_compile_output.chars().count() > budget / 2;
+        format!(
+            "Result\nCompilation log\n```{}\n{}\n```\nExecution log\n```{}\n{}\n```",
+            block,
+            preview(&escaped_compile_output, budget / 2),
+            block,
+            preview(&escaped_output, budget / 2)
+        )
+    };
+
+    if long_output {
+        edit_message = edit_message.new_attachment(CreateAttachment::bytes(
+            ansi::strip(&output),
+            "result_log.txt",
+        ));
+
+        if !compile_output.is_empty() {
+            edit_message = edit_message.new_attachment(CreateAttachment::bytes(
+                ansi::strip(&compile_output),
+                "compile_log.txt",
+            ));
+        }
+    }
+
+    let record = ExecutionRecord::new(source, output, compile_output, color);
+    edit_message = edit_message.components(record.components(long_output, None));
+
+    for file in files {
+        edit_message = edit_message.new_attachment(file);
+    }
+    content += &notes;
+
+    edit_message = edit_message.content(content);
+
+    message.edit(&ctx.http, edit_message).await?;
+    data.results.insert(message.id, record).await;
+
+    Ok(())
+}
+
+/// Notes for requested files that were not found, collapsed into a count
+/// when listing them would take up too much of the message.
+fn missing_files_note(paths: &[String]) -> String {
+    let note: String = paths
+        .iter()
+        .map(|path| format!("\nFile not found: `{}`", path))
+        .collect();
+    if note.chars().count() <= MISSING_FILES_NOTE_LIMIT {
+        note
+    } else {
+        format!("\n{} files not found", paths.len())
     }
 }
@@ -0,0 +1,475 @@
+//! Running code in containers, independent of the frontend that asked for it.
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bollard::container::LogOutput;
+use futures_util::StreamExt;
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+
+use crate::{
+    container_pool::{ContainerLease, ContainerPool},
+    error::BotError,
+    language::Language,
+    runtime::LogStream,
+};
+
+/// A file placed in the container before the source is compiled.
+#[derive(Clone, Debug)]
+pub struct JobFile {
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct JobLimits {
+    /// Time the program may run before it is stopped.
+    pub timeout: Duration,
+    /// Bytes of output kept from compiling and from running each. The step
+    /// is stopped once it wrote more.
+    pub output_bytes: usize,
+}
+
+/// A request to compile and run a program.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub language: Language,
+    pub source: String,
+    pub files: Vec<JobFile>,
+    pub stdin: Option<Vec<u8>>,
+    /// Paths fetched from the container once the program has finished.
+    pub downloads: Vec<String>,
+    /// Run in a terminal so that tools emit ANSI colors.
+    pub color: bool,
+    pub limits: JobLimits,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Compile,
+    Run,
+}
+
+/// Output of a job as it is produced.
+#[derive(Debug)]
+pub struct OutputChunk {
+    pub phase: Phase,
+    pub output: LogOutput,
+}
+
+#[derive(Clone, Debug)]
+pub struct Download {
+    pub path: String,
+    /// `None` when the path does not exist in the container.
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExecutionResult {
+    pub compile_output: String,
+    pub output: String,
+    pub timed_out: bool,
+    /// Output was cut off at the output limit of the job.
+    pub truncated: bool,
+    /// The program wrote to stderr. The compiler does not count, as it may
+    /// print warnings for working code.
+    pub stderr: bool,
+    /// The job was cancelled; nothing else in the result is meaningful.
+    pub cancelled: bool,
+    pub downloads: Vec<Download>,
+    /// CPU time used by the container, if it could be read.
+    pub cpu_time: Option<Duration>,
+    /// Wall time of the compile step, if the language has one.
+    pub compile_time: Option<Duration>,
+    pub run_time: Duration,
+}
+
+/// Why a run was stopped before it exited on its own.
+enum Stop {
+    Timeout,
+    Cancelled,
+}
+
+#[derive(Clone)]
+pub struct Executor {
+    pool: ContainerPool,
+}
+
+impl Executor {
+    pub fn new(pool: ContainerPool) -> Self {
+        Self { pool }
+    }
+
+    /// Acquires a container for `job`. The job runs once the returned
+    /// execution is started, so callers can report the container first.
+    pub async fn start(&self, job: Job) -> Result<Execution, BotError> {
+        let container = self.pool.get_container(job.language.clone()).await?;
+        Ok(Execution { container, job })
+    }
+
+    /// Runs `job` to completion.
+    pub async fn run(&self, job: Job) -> Result<ExecutionResult, BotError> {
+        self.start(job)
+            .await?
+            .run(Arc::new(Notify::new()), None)
+            .await
+    }
+}
+
+/// A job with the container it runs in.
+pub struct Execution {
+    container: ContainerLease,
+    job: Job,
+}
+
+impl Execution {
+    pub fn container_id(&self) -> &str {
+        &self.container.id
+    }
+
+    /// Uploads, compiles and runs the job, then releases its container.
+    ///
+    /// Output is sent to `events` as it arrives. Notifying `cancel` stops the
+    /// program and returns a cancelled result.
+    pub async fn run(
+        self,
+        cancel: Arc<Notify>,
+        events: Option<UnboundedSender<OutputChunk>>,
+    ) -> Result<ExecutionResult, BotError> {
+        let Execution { container, job } = self;
+
+        for file in job.files {
+            container.upload_file(file.data, &file.path).await?;
+        }
+        let file_name = format!("{}.{}", container.name, job.language.extension);
+        container.upload_source_file(&job.source, file_name).await?;
+
+        let mut result = ExecutionResult::default();
+
+        // Compiling and running share the time limit of the job.
+        let deadline = tokio::time::Instant::now() + job.limits.timeout;
+
+        let started = Instant::now();
+        let compile = until(deadline, &cancel, async {
+            let Some(mut output) = container.compile(job.color).await? else {
+                return Ok(false);
+            };
+            let collected = collect(
+                &mut output,
+                Phase::Compile,
+                &events,
+                &mut result.compile_output,
+                job.limits.output_bytes,
+            )
+            .await;
+            result.truncated |= collected.truncated;
+            Ok::<_, BotError>(true)
+        })
+        .await;
+        let mut stop = match compile {
+            Ok(compiled) => {
+                if compiled? {
+                    result.compile_time = Some(started.elapsed());
+                }
+                None
+            }
+            Err(stop) => {
+                result.compile_time = Some(started.elapsed());
+                Some(stop)
+            }
+        };
+
+        if stop.is_none() && !result.truncated {
+            let started = Instant::now();
+            // A stopped program keeps running until its container is
+            // released, but nothing reads its output any more.
+            let run = until(deadline, &cancel, async {
+                let mut output = container.run_code(job.color, job.stdin).await?;
+                let collected = collect(
+                    &mut output,
+                    Phase::Run,
+                    &events,
+                    &mut result.output,
+                    job.limits.output_bytes,
+                )
+                .await;
+                result.stderr |= collected.stderr;
+                result.truncated |= collected.truncated;
+                Ok::<_, BotError>(())
+            })
+            .await;
+            result.run_time = started.elapsed();
+            match run {
+                Ok(run) => run?,
+                Err(run_stop) => stop = Some(run_stop),
+            }
+        }
+
+        match stop {
+            Some(Stop::Timeout) => result.timed_out = true,
+            Some(Stop::Cancelled) => {
+                container.release().await;
+                result.cancelled = true;
+                return Ok(result);
+            }
+            None => {}
+        }
+
+        result.cpu_time = match container.cpu_usage().await {
+            Ok(cpu) => Some(cpu),
+            Err(e) => {
+                log::error!("Failed to read CPU usage of {}: {}", container.id, e);
+                None
+            }
+        };
+
+        for path in job.downloads {
+            let data = container.download_file(&path).await.ok();
+            result.downloads.push(Download { path, data });
+        }
+
+        container.release().await;
+        Ok(result)
+    }
+}
+
+/// Runs `future` until it completes, the deadline passes or the job is
+/// cancelled.
+async fn until<T>(
+    deadline: tokio::time::Instant,
+    cancel: &Notify,
+    future: impl Future<Output = T>,
+) -> Result<T, Stop> {
+    tokio::select! {
+        value = future => Ok(value),
+        _ = tokio::time::sleep_until(deadline) => Err(Stop::Timeout),
+        _ = cancel.notified() => Err(Stop::Cancelled),
+    }
+}
+
+/// What [`collect`] saw of the output of a step.
+struct Collected {
+    stderr: bool,
+    truncated: bool,
+}
+
+/// Appends the output of a compile or run step to `buf` until the step
+/// exits or wrote more than `limit` bytes, forwarding it to `events`. Output
+/// read before the future is dropped stays in `buf`.
+async fn collect(
+    output: &mut LogStream,
+    phase: Phase,
+    events: &Option<UnboundedSender<OutputChunk>>,
+    buf: &mut String,
+    limit: usize,
+) -> Collected {
+    let mut collected = Collected {
+        stderr: false,
+        truncated: false,
+    };
+    let mut read = 0;
+    while let Some(output) = output.next().await {
+        let mut output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                log::error!("Failed to read {:?} output: {}", phase, e);
+                break;
+            }
+        };
+        collected.stderr |= matches!(output, LogOutput::StdErr { .. });
+        if read + output.as_ref().len() > limit {
+            output = truncate(output, limit - read);
+            collected.truncated = true;
+        }
+        read += output.as_ref().len();
+        *buf += &output.to_string();
+        if let Some(events) = events {
+            let _ = events.send(OutputChunk { phase, output });
+        }
+        if collected.truncated {
+            break;
+        }
+    }
+    collected
+}
+
+/// The first `len` bytes of `output`.
+fn truncate(output: LogOutput, len: usize) -> LogOutput {
+    match output {
+        LogOutput::StdErr { message } => LogOutput::StdErr {
+            message: message.slice(..len),
+        },
+        LogOutput::StdOut { message } => LogOutput::StdOut {
+            message: message.slice(..len),
+        },
+        LogOutput::StdIn { message } => LogOutput::StdIn {
+            message: message.slice(..len),
+        },
+        LogOutput::Console { message } => LogOutput::Console {
+            message: message.slice(..len),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::fake::{self, FakeRuntime};
+
+    fn executor(runtime: &Arc<FakeRuntime>) -> Executor {
+        Executor::new(ContainerPool::new(
+            vec![fake::with_ready_image(fake::host(runtime), "text")],
+            "test".to_string(),
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        ))
+    }
+
+    fn job(language: Language) -> Job {
+        Job {
+            language,
+            source: "source".to_string(),
+            files: vec![],
+            stdin: None,
+            downloads: vec![],
+            color: false,
+            limits: JobLimits {
+                timeout: Duration::from_secs(5),
+                output_bytes: 1024,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn compiles_runs_and_downloads() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let job = Job {
+            files: vec![JobFile {
+                path: "input".to_string(),
+                data: b"input data".to_vec(),
+            }],
+            downloads: vec!["/out".to_string(), "/missing".to_string()],
+            ..job(fake::language("text", "cat input", Some("cp {file} out")))
+        };
+
+        let result = executor(&runtime).run(job).await.unwrap();
+        assert_eq!(result.compile_output, "");
+        assert_eq!(result.output, "input data");
+        assert!(!result.timed_out && !result.cancelled);
+        assert_eq!(result.downloads[0].data.as_deref(), Some(&b"source"[..]));
+        assert!(result.downloads[1].data.is_none());
+        assert!(runtime.container_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn passes_stdin_and_streams_output() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let job = Job {
+            stdin: Some(b"from stdin".to_vec()),
+            ..job(fake::language("text", "cat", None))
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let execution = executor(&runtime).start(job).await.unwrap();
+        let result = execution
+            .run(Arc::new(Notify::new()), Some(tx))
+            .await
+            .unwrap();
+        assert_eq!(result.output, "from stdin");
+        let chunk = rx.recv().await.unwrap();
+        assert_eq!(chunk.phase, Phase::Run);
+        assert_eq!(chunk.output.to_string(), "from stdin");
+    }
+
+    #[tokio::test]
+    async fn cuts_output_at_limit() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let yes = Job {
+            stdin: Some(vec![b'y'; 5000]),
+            ..job(fake::language("text", "cat", None))
+        };
+
+        let result = executor(&runtime).run(yes).await.unwrap();
+        assert!(result.truncated);
+        assert_eq!(result.output, "y".repeat(1024));
+
+        // A compiler that writes too much stops the job before the run.
+        let compiler = Job {
+            source: "x".repeat(5000),
+            ..job(fake::language("text", "echo ran", Some("cat {file}")))
+        };
+        let result = executor(&runtime).run(compiler).await.unwrap();
+        assert!(result.truncated);
+        assert_eq!(result.compile_output.len(), 1024);
+        assert_eq!(result.output, "");
+        assert!(runtime.container_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_at_timeout() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let job = Job {
+            limits: JobLimits {
+                timeout: Duration::from_millis(50),
+                output_bytes: 1024,
+            },
+            ..job(fake::language("text", "sleep", None))
+        };
+
+        let result = executor(&runtime).run(job).await.unwrap();
+        assert!(result.timed_out);
+        assert!(runtime.container_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn limits_compile_and_run_together() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let job = Job {
+            limits: JobLimits {
+                timeout: Duration::from_millis(50),
+                output_bytes: 1024,
+            },
+            ..job(fake::language("text", "echo ran", Some("sleep")))
+        };
+
+        let result = executor(&runtime).run(job).await.unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.output, "");
+        assert!(runtime.container_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let execution = executor(&runtime)
+            .start(job(fake::language("text", "sleep", None)))
+            .await
+            .unwrap();
+        let cancel = Arc::new(Notify::new());
+        cancel.notify_one();
+
+        let result = execution.run(cancel, None).await.unwrap();
+        assert!(result.cancelled);
+        assert!(runtime.container_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled_while_compiling() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let execution = executor(&runtime)
+            .start(job(fake::language("text", "echo ran", Some("sleep"))))
+            .await
+            .unwrap();
+        let cancel = Arc::new(Notify::new());
+        cancel.notify_one();
+
+        let result = execution.run(cancel, None).await.unwrap();
+        assert!(result.cancelled);
+        assert!(result.compile_time.is_some());
+        assert_eq!(result.output, "");
+        assert!(runtime.container_ids().is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::TriggerMode;
+
+/// Limits that override the global guild limits for one guild. They can only
+/// make the global limits stricter.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GuildLimits {
+    pub runs_per_minute: Option<usize>,
+    pub cpu_seconds_per_day: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GuildSettings {
+    /// Channels code is run in. Empty means every channel.
+    #[serde(default)]
+    pub allowed_channels: Vec<u64>,
+    /// Names of the languages enabled in this guild. Empty means all.
+    #[serde(default)]
+    pub enabled_languages: Vec<String>,
+    /// Overrides the global trigger mode in this guild.
+    #[serde(default)]
+    pub trigger: Option<TriggerMode>,
+    #[serde(default)]
+    pub limits: GuildLimits,
+}
+
+impl GuildSettings {
+    pub fn is_channel_allowed(&self, channel: u64) -> bool {
+        self.allowed_channels.is_empty() || self.allowed_channels.contains(&channel)
+    }
+
+    pub fn is_language_enabled(&self, language: &str) -> bool {
+        self.enabled_languages.is_empty() || self.enabled_languages.iter().any(|l| l == language)
+    }
+}
+
+/// Per-guild settings, persisted as YAML after every change.
+#[derive(Clone)]
+pub struct GuildSettingsStore {
+    path: PathBuf,
+    settings: Arc<Mutex<HashMap<u64, GuildSettings>>>,
+}
+
+impl GuildSettingsStore {
+    /// Loads the settings from `path`. A missing file means no guild has
+    /// been configured yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let settings = if path.exists() {
+            serde_yaml::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            path,
+            settings: Arc::new(Mutex::new(settings)),
+        })
+    }
+
+    pub async fn get(&self, guild: u64) -> GuildSettings {
+        self.settings
+            .lock()
+            .await
+            .get(&guild)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Applies `f` to the settings of `guild` and writes them to disk.
+    pub async fn update<F>(&self, guild: u64, f: F) -> Result<GuildSettings>
+    where
+        F: FnOnce(&mut GuildSettings),
+    {
+        let mut settings = self.settings.lock().await;
+        let guild_settings = settings.entry(guild).or_default();
+        f(guild_settings);
+        let updated = guild_settings.clone();
+
+        // The lock is held until the file is written so that writes happen
+        // in the order of the updates.
+        let yaml = serde_yaml::to_string(&*settings)?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || write_atomically(&path, &yaml)).await??;
+
+        Ok(updated)
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it, so
+/// that a crash never leaves a partially written file behind.
+fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persists_updates() {
+        let path = std::env::temp_dir().join(format!("guilds-{}.yaml", uuid::Uuid::new_v4()));
+        let store = GuildSettingsStore::load(&path).unwrap();
+        store
+            .update(1, |settings| settings.allowed_channels.push(2))
+            .await
+            .unwrap();
+
+        let loaded = GuildSettingsStore::load(&path).unwrap();
+        assert_eq!(loaded.get(1).await.allowed_channels, [2]);
+        assert!(!path.with_extension("yaml.tmp").exists());
+        fs::remove_file(path).unwrap();
+    }
+}
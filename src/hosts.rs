@@ -0,0 +1,225 @@
+//! Docker daemons that run jobs.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bollard::service::SystemInfo;
+
+use crate::{
+    config::{Config, SandboxConfig},
+    error::BotError,
+    image::ImageState,
+    language::Language,
+    runtime::{BollardRuntime, ContainerRuntime},
+};
+
+pub struct Host {
+    pub name: String,
+    pub runtime: Arc<dyn ContainerRuntime>,
+    /// Share of the jobs this host gets relative to the other hosts.
+    pub weight: usize,
+    pub labels: Vec<String>,
+    /// Sandbox options of languages that do not override them.
+    pub sandbox: SandboxConfig,
+    /// State of each language's image on this host.
+    images: Mutex<HashMap<String, ImageState>>,
+}
+
+impl Host {
+    pub fn new(
+        name: String,
+        runtime: Arc<dyn ContainerRuntime>,
+        weight: usize,
+        labels: Vec<String>,
+    ) -> Self {
+        Self {
+            name,
+            runtime,
+            weight: weight.max(1),
+            labels,
+            sandbox: SandboxConfig::default(),
+            images: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the host has every label `language` asks for.
+    pub fn supports(&self, language: &Language) -> bool {
+        language
+            .host_labels
+            .iter()
+            .all(|label| self.labels.contains(label))
+    }
+
+    pub fn image_state(&self, language: &Language) -> ImageState {
+        self.images
+            .lock()
+            .unwrap()
+            .get(&language.name)
+            .copied()
+            .unwrap_or(ImageState::Pending)
+    }
+
+    pub fn set_image_state(&self, language: &Language, state: ImageState) {
+        self.images
+            .lock()
+            .unwrap()
+            .insert(language.name.clone(), state);
+    }
+
+    /// Whether containers for `language` may be placed on this host. The
+    /// image must have been found or pulled, as creating the container
+    /// fails otherwise.
+    pub fn can_run(&self, language: &Language) -> bool {
+        self.supports(language) && self.image_state(language) == ImageState::Ready
+    }
+
+    /// Checks that the daemon supports the sandbox options of every language
+    /// this host may run. Unsupported options are configuration errors.
+    pub async fn check_sandbox(&self, languages: &[Language]) -> Result<()> {
+        let info = self.runtime.info().await?;
+        self.check_sandbox_with(info, languages)
+            .map_err(|e| BotError::Config(format!("{:#}", e)).into())
+    }
+
+    fn check_sandbox_with(&self, info: SystemInfo, languages: &[Language]) -> Result<()> {
+        let runtimes = info.runtimes.unwrap_or_default();
+        let security_options = info.security_options.unwrap_or_default();
+        let supports = |option: &str| {
+            security_options
+                .iter()
+                .any(|security| security.split(',').any(|part| part == option))
+        };
+
+        let sandboxes = std::iter::once(self.sandbox.clone()).chain(
+            languages
+                .iter()
+                .filter(|language| self.supports(language))
+                .map(|language| language.sandbox.or(&self.sandbox)),
+        );
+        for sandbox in sandboxes {
+            if let Some(runtime) = &sandbox.runtime {
+                if !runtimes.contains_key(runtime) {
+                    let mut available: Vec<&String> = runtimes.keys().collect();
+                    available.sort();
+                    bail!(
+                        "Runtime {} is not available, the daemon has {:?}",
+                        runtime,
+                        available
+                    );
+                }
+            }
+            if sandbox.seccomp_profile.is_some() && !supports("name=seccomp") {
+                bail!("The daemon does not support seccomp");
+            }
+            if sandbox.apparmor_profile.is_some() && !supports("name=apparmor") {
+                bail!("The daemon does not support AppArmor");
+            }
+            sandbox.security_opt()?;
+        }
+        Ok(())
+    }
+}
+
+/// Connects to the hosts in `config`. Unreachable hosts are skipped, unless
+/// none is left. Without configured hosts, `config.docker` is the only one.
+pub async fn connect(config: &Config) -> Result<Vec<Arc<Host>>> {
+    let mut hosts = vec![];
+    if config.hosts.is_empty() {
+        let runtime = BollardRuntime::connect(&config.docker).await?;
+        hosts.push(Host::new("local".to_string(), Arc::new(runtime), 1, vec![]));
+    } else {
+        for host in &config.hosts {
+            match BollardRuntime::connect(&host.docker).await {
+                Ok(runtime) => hosts.push(Host::new(
+                    host.name.clone(),
+                    Arc::new(runtime),
+                    host.weight,
+                    host.labels.clone(),
+                )),
+                Err(e) => log::error!("Skipping Docker host {}: {:#}", host.name, e),
+            }
+        }
+        if hosts.is_empty() {
+            return Err(anyhow!("None of the configured Docker hosts is reachable"));
+        }
+    }
+
+    // Checked up front, so a typo fails at startup instead of on every job.
+    for host in &mut hosts {
+        host.sandbox = config.sandbox.clone();
+        host.check_sandbox(&config.languages)
+            .await
+            .with_context(|| format!("Invalid sandbox options for host {}", host.name))?;
+    }
+    Ok(hosts.into_iter().map(Arc::new).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::fake::{self, FakeRuntime};
+
+    fn host(runtime: FakeRuntime, sandbox: SandboxConfig) -> Host {
+        Host {
+            sandbox,
+            ..Host::new("test".to_string(), Arc::new(runtime), 1, vec![])
+        }
+    }
+
+    fn runtime(name: &str) -> SandboxConfig {
+        SandboxConfig {
+            runtime: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_runtimes_against_the_daemon() {
+        let language = fake::language("text", "cat {file}", None);
+        let gvisor = host(FakeRuntime::new().with_runtime("runsc"), runtime("runsc"));
+        assert!(gvisor
+            .check_sandbox(std::slice::from_ref(&language))
+            .await
+            .is_ok());
+
+        let plain = host(FakeRuntime::new(), runtime("runsc"));
+        let error = plain.check_sandbox(&[]).await.unwrap_err();
+        assert!(error.to_string().contains("runsc"));
+
+        let kata = Language {
+            sandbox: runtime("kata-runtime"),
+            ..language.clone()
+        };
+        let elsewhere = Language {
+            host_labels: vec!["kata".to_string()],
+            ..kata.clone()
+        };
+        assert!(gvisor.check_sandbox(&[kata]).await.is_err());
+        // Languages the host never runs are not checked.
+        assert!(gvisor.check_sandbox(&[elsewhere]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn checks_security_profiles() {
+        let apparmor = SandboxConfig {
+            apparmor_profile: Some("docker-bot".to_string()),
+            ..Default::default()
+        };
+        assert!(host(FakeRuntime::new(), apparmor)
+            .check_sandbox(&[])
+            .await
+            .is_err());
+
+        let seccomp = SandboxConfig {
+            seccomp_profile: Some("/nonexistent/seccomp.json".to_string()),
+            ..Default::default()
+        };
+        assert!(host(FakeRuntime::new(), seccomp)
+            .check_sandbox(&[])
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use futures_util::{future::join_all, stream, StreamExt};
+
+use crate::{hosts::Host, language::Language};
+
+/// How many images are pulled at the same time across all hosts.
+const MAX_CONCURRENT_PULLS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageState {
+    /// The image has not been checked or is still being pulled.
+    Pending,
+    Ready,
+    /// The image could not be found or pulled.
+    Unavailable,
+}
+
+/// Makes sure the images of all configured languages exist on the hosts
+/// and remembers which languages can be used.
+#[derive(Clone)]
+pub struct ImageManager {
+    hosts: Vec<Arc<Host>>,
+}
+
+impl ImageManager {
+    pub fn new(hosts: Vec<Arc<Host>>) -> Self {
+        Self { hosts }
+    }
+
+    /// State of the language's image on the best host that supports it.
+    pub fn state(&self, language: &Language) -> ImageState {
+        let states: Vec<ImageState> = self
+            .hosts
+            .iter()
+            .filter(|host| host.supports(language))
+            .map(|host| host.image_state(language))
+            .collect();
+
+        if states.contains(&ImageState::Ready) {
+            ImageState::Ready
+        } else if states.contains(&ImageState::Pending) {
+            ImageState::Pending
+        } else {
+            ImageState::Unavailable
+        }
+    }
+
+    /// Returns the languages whose image is ready to use.
+    pub fn available(&self, languages: &[Language]) -> Vec<Language> {
+        languages
+            .iter()
+            .filter(|language| self.state(language) == ImageState::Ready)
+            .cloned()
+            .collect()
+    }
+
+    /// Checks the image of every language on every host supporting it and
+    /// pulls the missing ones.
+    ///
+    /// All images are inspected first, so that languages whose image is
+    /// already present are ready right away. The missing images are then
+    /// pulled, at most [`MAX_CONCURRENT_PULLS`] at a time. Languages whose
+    /// image cannot be pulled are marked unavailable instead of failing the
+    /// whole startup.
+    pub async fn prepare(&self, languages: &[Language]) {
+        let images = self.hosts.iter().flat_map(|host| {
+            languages
+                .iter()
+                .filter(|language| host.supports(language))
+                .map(move |language| (host, language))
+        });
+
+        let missing = join_all(images.map(|(host, language)| async move {
+            let reference = language.image_reference();
+            match host.runtime.image_digest(&reference).await {
+                Ok(Some(_)) => {
+                    host.set_image_state(language, ImageState::Ready);
+                    None
+                }
+                Ok(None) => Some((host, language)),
+                Err(e) => {
+                    Self::unavailable(host, language, &e);
+                    None
+                }
+            }
+        }))
+        .await;
+
+        stream::iter(missing.into_iter().flatten())
+            .for_each_concurrent(MAX_CONCURRENT_PULLS, |(host, language)| async move {
+                match Self::pull_image(host, &language.image_reference()).await {
+                    Ok(()) => host.set_image_state(language, ImageState::Ready),
+                    Err(e) => Self::unavailable(host, language, &e),
+                }
+            })
+            .await;
+    }
+
+    /// Content digest of the image a language runs in, or the image id when
+    /// the image was built locally and has no digest.
+    pub async fn digest(&self, language: &Language) -> Option<String> {
+        let host = self.hosts.iter().find(|host| {
+            host.supports(language) && host.image_state(language) == ImageState::Ready
+        })?;
+        host.runtime
+            .image_digest(&language.image_reference())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn pull_image(host: &Host, reference: &str) -> Result<()> {
+        println!("Pulling image {} on {}...", reference, host.name);
+        host.runtime.pull_image(reference).await?;
+        if host.runtime.image_digest(reference).await?.is_none() {
+            return Err(anyhow!("Image {} is missing after pulling it", reference));
+        }
+        println!("Pulled image {} on {}", reference, host.name);
+
+        Ok(())
+    }
+
+    fn unavailable(host: &Host, language: &Language, error: &anyhow::Error) {
+        log::error!(
+            "Image {} for {} is unavailable on {}: {}",
+            language.image_reference(),
+            language.name,
+            host.name,
+            error
+        );
+        host.set_image_state(language, ImageState::Unavailable);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::fake::{self, FakeRuntime};
+
+    #[tokio::test]
+    async fn prepares_present_and_missing_images() {
+        let runtime = Arc::new(FakeRuntime::new().with_missing_image("missing"));
+        let images = ImageManager::new(vec![fake::host(&runtime)]);
+        let present = fake::language("present", "cat", None);
+        let missing = fake::language("missing", "cat", None);
+        assert_eq!(images.state(&present), ImageState::Pending);
+
+        images.prepare(&[present.clone(), missing.clone()]).await;
+        assert_eq!(images.state(&present), ImageState::Ready);
+        assert_eq!(images.state(&missing), ImageState::Unavailable);
+        assert_eq!(images.available(&[present.clone(), missing]), vec![present]);
+    }
+}
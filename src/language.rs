@@ -1,6 +1,11 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
 use bollard::{container::Config, service::HostConfig};
 use serde::{Deserialize, Serialize};
 
+use crate::config::SandboxConfig;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Language {
     pub name: String,
@@ -10,6 +15,43 @@ pub struct Language {
     pub run_command: String,
     pub compile_command: Option<String>,
     pub image: String,
+    /// Pins the image to a content digest such as `sha256:...`.
+    pub image_digest: Option<String>,
+    /// Overrides the global `color_output` setting for this language.
+    pub color: Option<bool>,
+    /// Extra environment variables, such as `KEY=value`, set when running
+    /// with colored output.
+    #[serde(default)]
+    pub color_env: Vec<String>,
+    /// Idle containers kept warm even when nobody uses the language.
+    #[serde(default = "default_pool_min")]
+    pub pool_min: usize,
+    /// Upper bound for idle containers kept warm under heavy use.
+    #[serde(default = "default_pool_max")]
+    pub pool_max: usize,
+    /// Labels a host needs to run this language.
+    #[serde(default)]
+    pub host_labels: Vec<String>,
+    /// Overrides the global `sandbox` options for this language.
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    /// Program run by the self-test to check that the language works.
+    pub test: Option<LanguageTest>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LanguageTest {
+    pub source: String,
+    /// Expected output, compared without trailing whitespace.
+    pub expected_output: String,
+}
+
+fn default_pool_min() -> usize {
+    1
+}
+
+fn default_pool_max() -> usize {
+    3
 }
 
 impl Language {
@@ -22,25 +64,57 @@ impl Language {
     }
 
     pub fn get_compile_command(&self, file_name: String) -> Option<String> {
-        if let Some(compile) = self.compile_command.clone() {
-            Some(compile.replace("{file}", &file_name))
-        } else {
-            None
+        self.compile_command
+            .clone()
+            .map(|compile| compile.replace("{file}", &file_name))
+    }
+
+    /// Environment for compile and run commands. With colors enabled the
+    /// commands get a terminal and the variables most tools look at.
+    pub fn get_exec_env(&self, color: bool) -> Option<Vec<String>> {
+        if !color {
+            return None;
+        }
+
+        let mut env = vec![
+            "TERM=xterm-256color".to_string(),
+            "CLICOLOR_FORCE=1".to_string(),
+            "FORCE_COLOR=1".to_string(),
+        ];
+        env.extend(self.color_env.iter().cloned());
+        Some(env)
+    }
+
+    /// The image reference to create containers from, including the digest
+    /// when the image is pinned.
+    pub fn image_reference(&self) -> String {
+        match &self.image_digest {
+            Some(digest) => format!("{}@{}", self.image, digest),
+            None => self.image.clone(),
         }
     }
 
-    pub fn get_container_option(&self) -> Config<&str> {
-        Config {
-            image: Some(&self.image),
+    /// Options for a new container. `sandbox` is applied as is, so it
+    /// should already include this language's overrides.
+    pub fn get_container_option(
+        &self,
+        labels: HashMap<String, String>,
+        sandbox: &SandboxConfig,
+    ) -> Result<Config<String>> {
+        Ok(Config {
+            image: Some(self.image_reference()),
             tty: Some(true),
-            cmd: Some(vec!["/bin/sh"]),
+            cmd: Some(vec!["/bin/sh".to_string()]),
             network_disabled: Some(true),
             stop_timeout: Some(30),
+            labels: Some(labels),
             host_config: Some(HostConfig {
                 memory: Some(1024 * 1024 * 1024),
+                runtime: sandbox.runtime.clone(),
+                security_opt: Some(sandbox.security_opt()?),
                 ..Default::default()
             }),
             ..Default::default()
-        }
+        })
     }
 }
@@ -0,0 +1,408 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::guild_settings::GuildLimits;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LimitsConfig {
+    pub user_runs_per_minute: Option<usize>,
+    pub guild_runs_per_minute: Option<usize>,
+    pub user_cpu_seconds_per_day: Option<u64>,
+    pub guild_cpu_seconds_per_day: Option<u64>,
+    /// Users that are never limited. The bot owner is always exempt.
+    #[serde(default)]
+    pub exempt_users: Vec<u64>,
+    /// Members with any of these roles are never limited.
+    #[serde(default)]
+    pub exempt_roles: Vec<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Subject {
+    User(u64),
+    Guild(u64),
+}
+
+impl Subject {
+    fn describe(&self) -> &'static str {
+        match self {
+            Subject::User(_) => "You have",
+            Subject::Guild(_) => "This server has",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Rate {
+        subject: &'static str,
+        limit: usize,
+        retry_after: Duration,
+    },
+    Quota {
+        subject: &'static str,
+        limit: u64,
+    },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Rate {
+                subject,
+                limit,
+                retry_after,
+            } => write!(
+                f,
+                "{} reached the limit of {} runs per minute. Try again in {} seconds.",
+                subject,
+                limit,
+                retry_after.as_secs().max(1)
+            ),
+            LimitExceeded::Quota { subject, limit } => write!(
+                f,
+                "{} used up the daily quota of {} CPU seconds. It resets at midnight UTC.",
+                subject, limit
+            ),
+        }
+    }
+}
+
+/// A guild's own limit, which may lower the global one but not raise it.
+fn tighten<T: Ord>(global: Option<T>, guild: Option<T>) -> Option<T> {
+    match (global, guild) {
+        (Some(global), Some(guild)) => Some(global.min(guild)),
+        (global, guild) => guild.or(global),
+    }
+}
+
+#[derive(Default)]
+struct State {
+    runs: HashMap<Subject, VecDeque<Instant>>,
+    /// CPU time used per subject, together with the day it was counted on.
+    cpu: HashMap<Subject, (u64, Duration)>,
+}
+
+impl State {
+    /// Forgets runs that left the rate-limit window and CPU time counted on
+    /// earlier days, so that only recently active subjects are kept.
+    fn prune(&mut self, now: Instant, today: u64) {
+        self.runs.retain(|_, runs| {
+            while runs
+                .front()
+                .is_some_and(|t| now.duration_since(*t) >= MINUTE)
+            {
+                runs.pop_front();
+            }
+            !runs.is_empty()
+        });
+        self.cpu.retain(|_, (day, _)| *day == today);
+    }
+}
+
+/// Tracks runs and CPU time per user and guild and enforces the configured
+/// rate limits and daily quotas.
+#[derive(Clone)]
+pub struct UsageLimiter {
+    config: LimitsConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl UsageLimiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    pub fn is_exempt(&self, user: u64, roles: &[u64]) -> bool {
+        self.config.exempt_users.contains(&user)
+            || roles
+                .iter()
+                .any(|role| self.config.exempt_roles.contains(role))
+    }
+
+    fn today() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / DAY_SECS
+    }
+
+    fn subjects(
+        &self,
+        user: u64,
+        guild: Option<u64>,
+        overrides: &GuildLimits,
+    ) -> Vec<(Subject, Option<usize>, Option<u64>)> {
+        let mut subjects = vec![(
+            Subject::User(user),
+            self.config.user_runs_per_minute,
+            self.config.user_cpu_seconds_per_day,
+        )];
+        if let Some(guild) = guild {
+            subjects.push((
+                Subject::Guild(guild),
+                tighten(self.config.guild_runs_per_minute, overrides.runs_per_minute),
+                tighten(
+                    self.config.guild_cpu_seconds_per_day,
+                    overrides.cpu_seconds_per_day,
+                ),
+            ));
+        }
+        subjects
+    }
+
+    /// Checks every limit for a new run and records the run when it is allowed.
+    ///
+    /// `overrides` replaces the global guild limits for the guild of the run,
+    /// but can only make them stricter.
+    pub fn check(
+        &self,
+        user: u64,
+        guild: Option<u64>,
+        overrides: &GuildLimits,
+    ) -> Result<(), LimitExceeded> {
+        self.check_at(user, guild, overrides, Instant::now(), Self::today())
+    }
+
+    /// [`check`](Self::check) at the given time and day.
+    fn check_at(
+        &self,
+        user: u64,
+        guild: Option<u64>,
+        overrides: &GuildLimits,
+        now: Instant,
+        today: u64,
+    ) -> Result<(), LimitExceeded> {
+        let subjects = self.subjects(user, guild, overrides);
+        let mut state = self.state.lock().unwrap();
+        state.prune(now, today);
+
+        for (subject, runs_per_minute, cpu_seconds) in &subjects {
+            if let Some(limit) = cpu_seconds {
+                let used = match state.cpu.get(subject) {
+                    Some((day, used)) if *day == today => *used,
+                    _ => Duration::ZERO,
+                };
+                if used.as_secs() >= *limit {
+                    return Err(LimitExceeded::Quota {
+                        subject: subject.describe(),
+                        limit: *limit,
+                    });
+                }
+            }
+
+            if let Some(limit) = runs_per_minute {
+                let runs = state.runs.get(subject);
+                if runs.map_or(0, VecDeque::len) >= *limit {
+                    let retry_after = runs
+                        .and_then(VecDeque::front)
+                        .map_or(MINUTE, |t| MINUTE.saturating_sub(now.duration_since(*t)));
+                    return Err(LimitExceeded::Rate {
+                        subject: subject.describe(),
+                        limit: *limit,
+                        retry_after,
+                    });
+                }
+            }
+        }
+
+        for (subject, runs_per_minute, _) in &subjects {
+            if runs_per_minute.is_some() {
+                state.runs.entry(*subject).or_default().push_back(now);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds CPU time used by a finished run to the daily quotas.
+    pub fn record_cpu(&self, user: u64, guild: Option<u64>, cpu: Duration) {
+        self.record_cpu_on(user, guild, cpu, Self::today());
+    }
+
+    fn record_cpu_on(&self, user: u64, guild: Option<u64>, cpu: Duration, today: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.cpu.retain(|_, (day, _)| *day == today);
+
+        for (subject, _, _) in self.subjects(user, guild, &GuildLimits::default()) {
+            state
+                .cpu
+                .entry(subject)
+                .or_insert((today, Duration::ZERO))
+                .1 += cpu;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 20_000;
+
+    #[test]
+    fn limits_runs_per_minute() {
+        let limiter = UsageLimiter::new(LimitsConfig {
+            user_runs_per_minute: Some(2),
+            ..Default::default()
+        });
+        let none = GuildLimits::default();
+        let start = Instant::now();
+
+        assert!(limiter.check_at(1, None, &none, start, DAY).is_ok());
+        let later = start + Duration::from_secs(20);
+        assert!(limiter.check_at(1, None, &none, later, DAY).is_ok());
+
+        let blocked = start + Duration::from_secs(30);
+        match limiter.check_at(1, None, &none, blocked, DAY) {
+            Err(LimitExceeded::Rate { retry_after, .. }) => {
+                assert_eq!(retry_after, Duration::from_secs(30))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        // Other users have their own window.
+        assert!(limiter.check_at(2, None, &none, blocked, DAY).is_ok());
+
+        // The first run leaves the window after a minute.
+        assert!(limiter
+            .check_at(1, None, &none, start + MINUTE, DAY)
+            .is_ok());
+        assert!(limiter
+            .check_at(1, None, &none, start + MINUTE, DAY)
+            .is_err());
+    }
+
+    #[test]
+    fn limits_guild_runs_with_overrides() {
+        let limiter = UsageLimiter::new(LimitsConfig {
+            guild_runs_per_minute: Some(2),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let none = GuildLimits::default();
+
+        assert!(limiter.check_at(1, Some(10), &none, now, DAY).is_ok());
+        assert!(limiter.check_at(2, Some(10), &none, now, DAY).is_ok());
+        assert!(matches!(
+            limiter.check_at(3, Some(10), &none, now, DAY),
+            Err(LimitExceeded::Rate { limit: 2, .. })
+        ));
+        // Runs outside of the guild are not counted against it.
+        assert!(limiter.check_at(3, None, &none, now, DAY).is_ok());
+
+        let overrides = GuildLimits {
+            runs_per_minute: Some(1),
+            ..Default::default()
+        };
+        assert!(limiter.check_at(1, Some(20), &overrides, now, DAY).is_ok());
+        assert!(matches!(
+            limiter.check_at(2, Some(20), &overrides, now, DAY),
+            Err(LimitExceeded::Rate { limit: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn keeps_global_limits_over_larger_overrides() {
+        let limiter = UsageLimiter::new(LimitsConfig {
+            guild_runs_per_minute: Some(1),
+            guild_cpu_seconds_per_day: Some(10),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let overrides = GuildLimits {
+            runs_per_minute: Some(100),
+            cpu_seconds_per_day: Some(u64::MAX),
+        };
+
+        assert!(limiter.check_at(1, Some(10), &overrides, now, DAY).is_ok());
+        assert!(matches!(
+            limiter.check_at(2, Some(10), &overrides, now, DAY),
+            Err(LimitExceeded::Rate { limit: 1, .. })
+        ));
+
+        limiter.record_cpu_on(1, Some(20), Duration::from_secs(10), DAY);
+        assert!(matches!(
+            limiter.check_at(3, Some(20), &overrides, now, DAY),
+            Err(LimitExceeded::Quota { limit: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn resets_cpu_quota_daily() {
+        let limiter = UsageLimiter::new(LimitsConfig {
+            user_cpu_seconds_per_day: Some(10),
+            guild_cpu_seconds_per_day: Some(15),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let none = GuildLimits::default();
+
+        limiter.record_cpu_on(1, Some(10), Duration::from_secs(9), DAY);
+        assert!(limiter.check_at(1, Some(10), &none, now, DAY).is_ok());
+        limiter.record_cpu_on(1, Some(10), Duration::from_secs(1), DAY);
+        assert!(matches!(
+            limiter.check_at(1, Some(10), &none, now, DAY),
+            Err(LimitExceeded::Quota { limit: 10, .. })
+        ));
+
+        // The guild quota counts the CPU time of all its members.
+        limiter.record_cpu_on(2, Some(10), Duration::from_secs(5), DAY);
+        assert!(matches!(
+            limiter.check_at(3, Some(10), &none, now, DAY),
+            Err(LimitExceeded::Quota { limit: 15, .. })
+        ));
+
+        assert!(limiter.check_at(1, Some(10), &none, now, DAY + 1).is_ok());
+        limiter.record_cpu_on(1, None, Duration::from_secs(9), DAY + 1);
+        assert!(limiter.check_at(1, None, &none, now, DAY + 1).is_ok());
+    }
+
+    #[test]
+    fn forgets_idle_subjects() {
+        let limiter = UsageLimiter::new(LimitsConfig {
+            user_runs_per_minute: Some(5),
+            guild_runs_per_minute: Some(5),
+            ..Default::default()
+        });
+        let none = GuildLimits::default();
+        let start = Instant::now();
+
+        assert!(limiter.check_at(1, Some(10), &none, start, DAY).is_ok());
+        limiter.record_cpu_on(1, Some(10), Duration::from_secs(1), DAY);
+        assert_eq!(limiter.state.lock().unwrap().runs.len(), 2);
+        assert_eq!(limiter.state.lock().unwrap().cpu.len(), 2);
+
+        assert!(limiter
+            .check_at(2, None, &none, start + MINUTE, DAY + 1)
+            .is_ok());
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.runs.keys().collect::<Vec<_>>(), [&Subject::User(2)]);
+        assert!(state.cpu.is_empty());
+    }
+
+    #[test]
+    fn exempts_users_and_roles() {
+        let limiter = UsageLimiter::new(LimitsConfig {
+            exempt_users: vec![1],
+            exempt_roles: vec![5],
+            ..Default::default()
+        });
+
+        assert!(limiter.is_exempt(1, &[]));
+        assert!(limiter.is_exempt(2, &[4, 5]));
+        assert!(!limiter.is_exempt(2, &[4]));
+    }
+}
@@ -1,43 +1,67 @@
+mod ansi;
+mod api;
+mod cli;
+mod commands;
 mod config;
 mod container_pool;
 mod docker;
+mod error;
 mod event_handler;
+mod executor;
+mod guild_settings;
+mod hosts;
+mod image;
 mod language;
+mod limits;
+mod output;
+mod results;
+mod runtime;
+mod scheduler;
+mod selftest;
 
-use std::{collections::HashSet, env, fs::File, io::Read, sync::Arc};
+use std::{collections::HashSet, env, fs, sync::Arc, time::Duration};
 
+use api::Api;
 use config::Config;
 
 use container_pool::ContainerPool;
+use error::BotError;
 use event_handler::event_handler;
+use executor::Executor;
+use guild_settings::GuildSettingsStore;
+use image::ImageManager;
+use limits::UsageLimiter;
 use poise::{
     serenity_prelude::{self as serenity, futures::lock::Mutex, UserId},
     PrefixFrameworkOptions,
 };
+use results::ResultStore;
+use scheduler::{Scheduler, SchedulerLimits};
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
-#[allow(unused)]
 type Context<'a> = poise::Context<'a, Data, Error>;
 
 pub struct Data {
     pub config: Arc<Mutex<Config>>,
-    pub container_pool: Arc<Mutex<ContainerPool>>,
+    pub executor: Executor,
+    pub images: ImageManager,
+    pub scheduler: Scheduler,
+    pub limiter: UsageLimiter,
+    pub guild_settings: GuildSettingsStore,
+    pub results: ResultStore,
 }
 
-fn load_config() -> Option<Config> {
-    if let Ok(mut config_file) = File::open("config.yaml") {
-        let mut buf = String::default();
-        config_file.read_to_string(&mut buf).unwrap();
-        let config = serde_yaml::from_str::<Config>(&buf);
+fn load_config() -> Result<Config, BotError> {
+    let buf = fs::read_to_string("config.yaml")
+        .map_err(|e| BotError::Config(format!("Failed to read config.yaml: {}", e)))?;
+    serde_yaml::from_str::<Config>(&buf)
+        .map_err(|e| BotError::Config(format!("Invalid config.yaml: {}", e)))
+}
 
-        if let Ok(config) = config {
-            Some(config)
-        } else {
-            None
-        }
-    } else {
-        None
-    }
+/// Prints `error` and exits, for failures before the bot is running.
+fn exit_with(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
 }
 
 #[tokio::main]
@@ -45,27 +69,91 @@ async fn main() -> Result<(), ()> {
     env::set_var("RUST_LOG", "error");
     env_logger::init();
 
-    let config = load_config().unwrap();
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let config = load_config().unwrap_or_else(|e| exit_with(e));
+
+    if command != cli::Command::Bot {
+        std::process::exit(cli::run(command, config).await);
+    }
 
     let intents =
         serenity::GatewayIntents::non_privileged() | serenity::GatewayIntents::MESSAGE_CONTENT;
 
-    let mut pool = ContainerPool::new();
+    let hosts = hosts::connect(&config)
+        .await
+        .unwrap_or_else(|e| exit_with(BotError::from(e)));
+    let pool = ContainerPool::new(
+        hosts.clone(),
+        config.instance_id(),
+        Duration::from_secs(config.demand_window),
+        Duration::from_secs(config.container_max_age),
+    );
     pool.cleanup().await;
-    for _ in 0..3 {
-        for language in &config.languages {
-            pool.add_container(language.clone()).await;
+    pool.spawn_reaper(
+        Duration::from_secs(config.container_ttl),
+        Duration::from_secs(config.reaper_interval),
+    );
+
+    let scheduler = Scheduler::new(SchedulerLimits {
+        global: config.max_concurrent_jobs,
+        per_user: config.max_jobs_per_user,
+        per_guild: config.max_jobs_per_guild,
+    });
+
+    let limiter = UsageLimiter::new(config.limits.clone());
+    let guild_settings =
+        GuildSettingsStore::load(&config.guild_settings_path).unwrap_or_else(|e| {
+            exit_with(BotError::Config(format!(
+                "Failed to load {}: {}",
+                config.guild_settings_path, e
+            )))
+        });
+
+    let images = ImageManager::new(hosts);
+    tokio::spawn({
+        let pool = pool.clone();
+        let images = images.clone();
+        let config = config.clone();
+        async move {
+            images.prepare(&config.languages).await;
+            let languages = images.available(&config.languages);
+            pool.spawn_warmup(languages.clone(), config.warmup_concurrency);
+            pool.spawn_rebalancer(languages, Duration::from_secs(config.rebalance_interval));
         }
+    });
+
+    let executor = Executor::new(pool);
+    let shared_config = Arc::new(Mutex::new(config.clone()));
+    if let Some(http) = config.http.clone() {
+        Api::spawn(
+            http,
+            shared_config.clone(),
+            executor.clone(),
+            images.clone(),
+            scheduler.clone(),
+        );
     }
 
     let framework = poise::Framework::builder()
         .setup({
-            let config = config.clone();
-            move |_ctx, _ready, _framework| {
+            move |ctx, _ready, framework| {
                 Box::pin(async move {
+                    poise::builtins::register_globally(ctx, &framework.options().commands).await?;
                     Ok(Data {
-                        config: Arc::new(Mutex::new(config)),
-                        container_pool: Arc::new(Mutex::new(pool)),
+                        config: shared_config,
+                        executor,
+                        images,
+                        scheduler,
+                        limiter,
+                        guild_settings,
+                        results: ResultStore::new(),
                     })
                 })
             }
@@ -74,12 +162,13 @@ async fn main() -> Result<(), ()> {
             event_handler: |ctx, event, framework, data| {
                 Box::pin(event_handler(ctx, event, framework, data))
             },
-            commands: vec![],
+            commands: vec![commands::sandbox(), commands::selftest()],
             prefix_options: PrefixFrameworkOptions {
                 prefix: Some(config.prefix),
                 ..Default::default()
             },
             owners: HashSet::from([UserId::new(config.owner)]),
+            skip_checks_for_owners: true,
             ..Default::default()
         })
         .build();
@@ -88,7 +177,9 @@ async fn main() -> Result<(), ()> {
         .framework(framework)
         .await;
 
-    client.unwrap().start().await.unwrap();
+    if let Err(e) = async { client?.start().await }.await {
+        exit_with(BotError::from(e));
+    }
 
     Ok(())
 }
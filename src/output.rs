@@ -0,0 +1,138 @@
+/// Maximum length of a Discord message in characters.
+pub const MESSAGE_LIMIT: usize = 2000;
+
+/// Makes `text` safe to put inside a code block by breaking every run of
+/// three backticks with a zero-width space, so it can never close the block.
+/// This grows the text, so measure and cut it after escaping. Escaping
+/// escaped text changes nothing.
+pub fn escape_code_block(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut backticks = 0;
+    for c in text.chars() {
+        if c == '`' {
+            if backticks == 2 {
+                escaped.push('\u{200b}');
+                backticks = 0;
+            }
+            backticks += 1;
+        } else {
+            backticks = 0;
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Shortens `text` to at most `limit` characters by keeping its head and
+/// tail and replacing the middle with a marker naming the omitted line count.
+pub fn preview(text: &str, limit: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= limit {
+        return text.to_string();
+    }
+
+    // The marker never names more lines than the text has.
+    let marker = |omitted: usize| format!("\n… {} lines omitted …\n", omitted);
+    let half = limit.saturating_sub(marker(text.lines().count()).chars().count()) / 2;
+    let head: String = chars[..half].iter().collect();
+    let tail: String = chars[chars.len() - half..].iter().collect();
+    // Cut at line boundaries where possible so partial lines are not shown.
+    let head = match head.rfind('\n') {
+        Some(i) if i > 0 => head[..i].to_string(),
+        _ => head,
+    };
+    let tail = match tail.find('\n') {
+        Some(i) if i + 1 < tail.len() => tail[i + 1..].to_string(),
+        _ => tail,
+    };
+
+    let omitted = text
+        .lines()
+        .count()
+        .saturating_sub(head.lines().count() + tail.lines().count());
+    format!("{}{}{}", head, marker(omitted), tail)
+}
+
+/// Splits `text` into pages of at most `page_size` characters, breaking at
+/// line ends when a line fits on the page.
+pub fn paginate(text: &str, page_size: usize) -> Vec<String> {
+    let mut pages = vec![];
+    let mut page = String::new();
+    let mut page_len = 0;
+
+    for line in text.split_inclusive('\n') {
+        let mut line = line;
+        loop {
+            let line_len = line.chars().count();
+            if page_len + line_len <= page_size {
+                page.push_str(line);
+                page_len += line_len;
+                break;
+            }
+            if page_len > 0 {
+                pages.push(std::mem::take(&mut page));
+                page_len = 0;
+                continue;
+            }
+            // The line alone does not fit on a page, split it.
+            let split = line
+                .char_indices()
+                .nth(page_size)
+                .map_or(line.len(), |(i, _)| i);
+            pages.push(line[..split].to_string());
+            line = &line[split..];
+            if line.is_empty() {
+                break;
+            }
+        }
+    }
+
+    if !page.is_empty() || pages.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_fences_only() {
+        assert_eq!(escape_code_block("a `b` ``c``"), "a `b` ``c``");
+        assert_eq!(escape_code_block("```"), "``\u{200b}`");
+        assert_eq!(escape_code_block("``````"), "``\u{200b}``\u{200b}``");
+        let escaped = escape_code_block(&"`".repeat(1700));
+        assert!(!escaped.contains("```"));
+        assert!(escaped.chars().count() < 1700 * 3 / 2);
+        assert_eq!(escape_code_block(&escaped), escaped);
+    }
+
+    #[test]
+    fn previews_head_and_tail() {
+        assert_eq!(preview("short", 10), "short");
+
+        let text: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let shown = preview(&text, 100);
+        assert!(shown.starts_with("line 0\n"));
+        assert!(shown.ends_with("line 99\n"));
+        assert!(shown.contains("lines omitted"));
+        assert!(shown.chars().count() <= 100);
+
+        let long_lines = "x".repeat(5000);
+        assert!(preview(&long_lines, 100).chars().count() <= 100);
+    }
+
+    #[test]
+    fn paginates_at_line_ends() {
+        assert_eq!(paginate("", 10), vec![""]);
+        assert_eq!(paginate("aaa\nbbb\nccc\n", 8), vec!["aaa\nbbb\n", "ccc\n"]);
+        assert_eq!(paginate("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+
+        let text = "é".repeat(25);
+        let pages = paginate(&text, 10);
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|page| page.chars().count() <= 10));
+        assert_eq!(pages.concat(), text);
+    }
+}
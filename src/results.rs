@@ -0,0 +1,257 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use poise::serenity_prelude::{
+    Attachment, ButtonStyle, ChannelId, CreateActionRow, CreateButton, GuildId, MessageId, RoleId,
+    UserId,
+};
+use tokio::sync::{Mutex, Notify};
+
+use crate::output::{escape_code_block, paginate, MESSAGE_LIMIT};
+
+/// Number of results kept for browsing; older ones are forgotten.
+const CAPACITY: usize = 256;
+
+/// Characters of output per page, leaving room for the page header.
+const PAGE_SIZE: usize = MESSAGE_LIMIT - 100;
+
+pub const PAGE_BUTTON_PREFIX: &str = "dockerbot:page:";
+pub const RERUN_BUTTON: &str = "dockerbot:rerun";
+pub const DELETE_BUTTON: &str = "dockerbot:delete";
+pub const FILE_BUTTON: &str = "dockerbot:file";
+
+/// The user message an execution was started from, with everything needed
+/// to run it again.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub author: UserId,
+    /// Roles of the author when the message was sent.
+    pub roles: Vec<RoleId>,
+    pub guild: Option<GuildId>,
+    pub channel: ChannelId,
+    pub message: MessageId,
+    /// Language tag of the code block.
+    pub language: String,
+    pub code: String,
+    /// Paths to download after the run.
+    pub paths: Vec<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+/// One page of escaped output.
+pub struct Page {
+    /// Which log the page belongs to, shown when there is a compile log.
+    pub log: Option<&'static str>,
+    pub text: String,
+}
+
+/// Output of an execution, kept so that its reply can be paged through,
+/// rerun or shown as a file.
+pub struct ExecutionRecord {
+    pub source: Source,
+    pub output: String,
+    pub compile_output: String,
+    /// Pages of the compile log, if any, followed by the output.
+    pub pages: Vec<Page>,
+    /// Whether the output contains ANSI escapes for an `ansi` code block.
+    pub ansi: bool,
+}
+
+impl ExecutionRecord {
+    pub fn new(source: Source, output: String, compile_output: String, ansi: bool) -> Self {
+        // Paged after escaping, which grows the text.
+        let pages = |text: &str, log| {
+            paginate(&escape_code_block(text), PAGE_SIZE)
+                .into_iter()
+                .map(move |text| Page { log, text })
+        };
+        let pages = if compile_output.is_empty() {
+            pages(&output, None).collect()
+        } else {
+            pages(&compile_output, Some("Compilation log"))
+                .chain(pages(&output, Some("Execution log")))
+                .collect()
+        };
+
+        Self {
+            source,
+            pages,
+            output,
+            compile_output,
+            ansi,
+        }
+    }
+
+    pub fn render_page(&self, page: usize) -> String {
+        let page_number = page + 1;
+        let page = &self.pages[page];
+        format!(
+            "Result (page {}/{})\n{}```{}\n{}\n```",
+            page_number,
+            self.pages.len(),
+            page.log.map(|log| format!("{}\n", log)).unwrap_or_default(),
+            if self.ansi { "ansi" } else { "" },
+            page.text
+        )
+    }
+
+    /// Buttons to move from `page` to its neighbours. `None` is the inline
+    /// preview shown before any page was opened.
+    fn page_buttons(&self, page: Option<usize>) -> CreateActionRow {
+        let button = |target: usize, label: &str| {
+            CreateButton::new(format!("{}{}", PAGE_BUTTON_PREFIX, target)).label(label)
+        };
+
+        let buttons = match page {
+            None => vec![button(0, "Browse output")],
+            Some(page) => vec![
+                button(page.saturating_sub(1), "◀").disabled(page == 0),
+                CreateButton::new("dockerbot:page-indicator")
+                    .label(format!("{}/{}", page + 1, self.pages.len()))
+                    .disabled(true),
+                button(page + 1, "▶").disabled(page + 1 >= self.pages.len()),
+            ],
+        };
+
+        CreateActionRow::Buttons(buttons)
+    }
+
+    /// All components of the reply while `page` is shown. Paging buttons
+    /// are only added when `paged` is set and there is more than one page.
+    pub fn components(&self, paged: bool, page: Option<usize>) -> Vec<CreateActionRow> {
+        let mut rows = vec![];
+        if paged && self.pages.len() > 1 {
+            rows.push(self.page_buttons(page));
+        }
+        rows.push(CreateActionRow::Buttons(vec![
+            CreateButton::new(RERUN_BUTTON).label("Run again"),
+            CreateButton::new(FILE_BUTTON).label("Show as file"),
+            delete_button(),
+        ]));
+        rows
+    }
+}
+
+pub fn delete_button() -> CreateButton {
+    CreateButton::new(DELETE_BUTTON)
+        .label("Delete")
+        .style(ButtonStyle::Danger)
+}
+
+/// An execution that has not finished yet.
+struct ActiveRun {
+    source: Source,
+    cancel: Arc<Notify>,
+}
+
+type Records = VecDeque<(MessageId, Arc<ExecutionRecord>)>;
+
+/// Running and recent executions, keyed by the id of the reply showing them.
+#[derive(Clone, Default)]
+pub struct ResultStore {
+    records: Arc<Mutex<Records>>,
+    active: Arc<Mutex<HashMap<MessageId, ActiveRun>>>,
+}
+
+impl ResultStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a running execution. The returned notify fires when the
+    /// execution is cancelled through its reply.
+    pub async fn start(&self, reply: MessageId, source: Source) -> Arc<Notify> {
+        let cancel = Arc::new(Notify::new());
+        self.active.lock().await.insert(
+            reply,
+            ActiveRun {
+                source,
+                cancel: cancel.clone(),
+            },
+        );
+        cancel
+    }
+
+    /// Marks the execution shown in `reply` as finished. Returns `false`
+    /// when it was cancelled in the meantime.
+    pub async fn finish(&self, reply: MessageId) -> bool {
+        self.active.lock().await.remove(&reply).is_some()
+    }
+
+    pub async fn insert(&self, reply: MessageId, record: ExecutionRecord) {
+        let mut records = self.records.lock().await;
+        if records.len() >= CAPACITY {
+            records.pop_front();
+        }
+        records.push_back((reply, Arc::new(record)));
+    }
+
+    pub async fn get(&self, reply: MessageId) -> Option<Arc<ExecutionRecord>> {
+        self.records
+            .lock()
+            .await
+            .iter()
+            .find(|(id, _)| *id == reply)
+            .map(|(_, record)| record.clone())
+    }
+
+    /// The source of a running or finished execution.
+    pub async fn source(&self, reply: MessageId) -> Option<Source> {
+        if let Some(run) = self.active.lock().await.get(&reply) {
+            return Some(run.source.clone());
+        }
+        self.get(reply).await.map(|record| record.source.clone())
+    }
+
+    /// Stops a running execution, if any, and forgets everything about it.
+    pub async fn remove(&self, reply: MessageId) {
+        if let Some(run) = self.active.lock().await.remove(&reply) {
+            run.cancel.notify_one();
+        }
+        self.records.lock().await.retain(|(id, _)| *id != reply);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> Source {
+        Source {
+            author: UserId::new(1),
+            roles: vec![],
+            guild: None,
+            channel: ChannelId::new(1),
+            message: MessageId::new(1),
+            language: "text".to_string(),
+            code: String::new(),
+            paths: vec![],
+            attachments: vec![],
+        }
+    }
+
+    #[test]
+    fn pages_compile_log_before_output() {
+        let compile_output = "warning\n".repeat(PAGE_SIZE / 6);
+        let record = ExecutionRecord::new(source(), "hello".to_string(), compile_output, false);
+
+        assert_eq!(record.pages.len(), 3);
+        assert!(record
+            .render_page(0)
+            .starts_with("Result (page 1/3)\nCompilation log\n```"));
+        assert!(record
+            .render_page(2)
+            .starts_with("Result (page 3/3)\nExecution log\n```"));
+        assert!(record.render_page(2).contains("hello"));
+    }
+
+    #[test]
+    fn pages_output_without_heading() {
+        let record = ExecutionRecord::new(source(), "hello".to_string(), String::new(), false);
+
+        assert_eq!(record.pages.len(), 1);
+        assert_eq!(record.render_page(0), "Result (page 1/1)\n```\nhello\n```");
+    }
+}
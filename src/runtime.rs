@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use bollard::{
+    container::{
+        Config, CreateContainerOptions, DownloadFromContainerOptions, ListContainersOptions,
+        LogOutput, RemoveContainerOptions, StatsOptions, UploadToContainerOptions,
+    },
+    exec::{CreateExecOptions, StartExecResults},
+    image::CreateImageOptions,
+    service::{ContainerStateStatusEnum, ContainerSummary, SystemInfo},
+    Docker, API_DEFAULT_VERSION,
+};
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use tokio::{io::AsyncWriteExt, task::AbortHandle};
+
+use crate::config::{DockerConfig, TlsConfig};
+
+#[cfg(test)]
+pub mod fake;
+
+/// Output of a command, in the order it was written.
+pub type LogStream = BoxStream<'static, Result<LogOutput>>;
+
+/// Options of a command executed inside a container.
+#[derive(Clone, Debug, Default)]
+pub struct ExecOptions {
+    /// Allocate a terminal, which merges stderr into stdout.
+    pub tty: bool,
+    /// Environment variables in `KEY=value` form.
+    pub env: Option<Vec<String>>,
+    /// Written to the command's standard input, which is closed afterwards.
+    pub stdin: Option<Vec<u8>>,
+}
+
+/// The operations the bot needs from a container engine.
+///
+/// Everything that touches containers goes through this trait, so the pool
+/// and the execution flow can run against an in-memory fake in tests.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    /// Creates a container and returns its id.
+    async fn create(&self, name: &str, config: Config<String>) -> Result<String>;
+
+    async fn start(&self, id: &str) -> Result<()>;
+
+    /// Extracts a tar archive, optionally gzip compressed, into `/`.
+    async fn upload(&self, id: &str, archive: Vec<u8>) -> Result<()>;
+
+    /// Runs `cmd` and streams its output until it exits.
+    async fn exec(&self, id: &str, cmd: Vec<String>, options: ExecOptions) -> Result<LogStream>;
+
+    /// Returns `path` packed into a tar archive.
+    async fn download(&self, id: &str, path: &str) -> Result<Vec<u8>>;
+
+    /// Removes a container, killing it first if it is running.
+    async fn remove(&self, id: &str) -> Result<()>;
+
+    /// Lists all containers, running or not, carrying the `key=value` label.
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>>;
+
+    /// Returns the state of a container, or `None` if it does not exist.
+    async fn status(&self, id: &str) -> Result<Option<ContainerStateStatusEnum>>;
+
+    /// CPU time consumed by a container since it was started.
+    async fn cpu_usage(&self, id: &str) -> Result<Duration>;
+
+    /// Content digest of a local image, or its id when it has no digest.
+    /// Returns `None` if the image does not exist locally.
+    async fn image_digest(&self, reference: &str) -> Result<Option<String>>;
+
+    /// Pulls an image from its registry.
+    async fn pull_image(&self, reference: &str) -> Result<()>;
+
+    /// Information about the daemon, such as its runtimes.
+    async fn info(&self) -> Result<SystemInfo>;
+}
+
+/// Aborts a background task once the stream owning it is dropped.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runtime backed by a Docker daemon.
+pub struct BollardRuntime {
+    docker: Docker,
+}
+
+impl BollardRuntime {
+    pub fn new(docker: Docker) -> Self {
+        Self { docker }
+    }
+
+    /// Connects to the daemon configured in `config` and checks that it
+    /// answers, so that a wrong endpoint is reported at startup.
+    pub async fn connect(config: &DockerConfig) -> Result<Self> {
+        let host = config
+            .host
+            .clone()
+            .or_else(|| env::var("DOCKER_HOST").ok())
+            .map(|host| {
+                if host.starts_with('/') {
+                    format!("unix://{}", host)
+                } else {
+                    host
+                }
+            });
+
+        let docker = match &host {
+            None => Docker::connect_with_local_defaults()?,
+            Some(host) if host.starts_with("unix://") => {
+                Docker::connect_with_unix(host, config.timeout, API_DEFAULT_VERSION)?
+            }
+            Some(host) => match tls_files(config) {
+                Some(tls) => Docker::connect_with_ssl(
+                    host,
+                    Path::new(&tls.key),
+                    Path::new(&tls.cert),
+                    Path::new(&tls.ca),
+                    config.timeout,
+                    API_DEFAULT_VERSION,
+                )?,
+                None if host.starts_with("https://") => {
+                    return Err(anyhow!(
+                        "{} needs TLS certificates, set docker.tls or DOCKER_CERT_PATH",
+                        host
+                    ))
+                }
+                None => Docker::connect_with_http(host, config.timeout, API_DEFAULT_VERSION)?,
+            },
+        };
+
+        docker.ping().await.with_context(|| {
+            format!(
+                "Cannot reach the Docker daemon at {}",
+                host.as_deref().unwrap_or("the local socket")
+            )
+        })?;
+        Ok(Self::new(docker))
+    }
+}
+
+/// TLS files from the config, or from `DOCKER_CERT_PATH` when
+/// `DOCKER_TLS_VERIFY` is set like the docker CLI does.
+fn tls_files(config: &DockerConfig) -> Option<TlsConfig> {
+    if let Some(tls) = &config.tls {
+        return Some(tls.clone());
+    }
+    if env::var("DOCKER_TLS_VERIFY").map_or(true, |verify| verify.is_empty()) {
+        return None;
+    }
+    let dir = PathBuf::from(env::var("DOCKER_CERT_PATH").ok()?);
+    let file = |name: &str| dir.join(name).to_string_lossy().to_string();
+    Some(TlsConfig {
+        ca: file("ca.pem"),
+        cert: file("cert.pem"),
+        key: file("key.pem"),
+    })
+}
+
+/// Splits an image reference into the `fromImage` and `tag` parameters of
+/// the pull API. Untagged references default to `latest` since an empty tag
+/// would pull every tag of the repository.
+fn split_reference(reference: &str) -> (&str, &str) {
+    if reference.contains('@') {
+        return (reference, "");
+    }
+
+    let name_start = reference.rfind('/').map_or(0, |i| i + 1);
+    match reference[name_start..].rfind(':') {
+        Some(i) => (
+            &reference[..name_start + i],
+            &reference[name_start + i + 1..],
+        ),
+        None => (reference, "latest"),
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for BollardRuntime {
+    async fn create(&self, name: &str, config: Config<String>) -> Result<String> {
+        Ok(self
+            .docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name,
+                    platform: None,
+                }),
+                config,
+            )
+            .await?
+            .id)
+    }
+
+    async fn start(&self, id: &str) -> Result<()> {
+        self.docker.start_container::<String>(id, None).await?;
+        Ok(())
+    }
+
+    async fn upload(&self, id: &str, archive: Vec<u8>) -> Result<()> {
+        self.docker
+            .upload_to_container(
+                id,
+                Some(UploadToContainerOptions {
+                    path: "/",
+                    ..Default::default()
+                }),
+                archive.into(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn exec(&self, id: &str, cmd: Vec<String>, options: ExecOptions) -> Result<LogStream> {
+        let exec = self
+            .docker
+            .create_exec(
+                id,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stdin: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(cmd),
+                    tty: Some(options.tty),
+                    env: options.env,
+                    ..Default::default()
+                },
+            )
+            .await?
+            .id;
+
+        match self.docker.start_exec(&exec, None).await? {
+            StartExecResults::Attached { output, mut input } => {
+                let Some(stdin) = options.stdin else {
+                    return Ok(output.map_err(Into::into).boxed());
+                };
+                // Written in the background, as a program that never reads
+                // its input would block the write. Errors mean the program
+                // exited or the output was dropped, so they are ignored.
+                let writer = tokio::spawn(async move {
+                    if input.write_all(&stdin).await.is_ok() {
+                        let _ = input.shutdown().await;
+                    }
+                });
+                let writer = AbortOnDrop(writer.abort_handle());
+                Ok(output
+                    .map_err(Into::into)
+                    .map(move |output| {
+                        let _ = &writer;
+                        output
+                    })
+                    .boxed())
+            }
+            StartExecResults::Detached => unreachable!(),
+        }
+    }
+
+    async fn download(&self, id: &str, path: &str) -> Result<Vec<u8>> {
+        let options = Some(DownloadFromContainerOptions { path });
+        let mut download = self.docker.download_from_container(id, options);
+        let mut result: Vec<u8> = vec![];
+        while let Some(d) = download.next().await {
+            result.append(&mut d?.into());
+        }
+        Ok(result)
+    }
+
+    async fn remove(&self, id: &str) -> Result<()> {
+        self.docker
+            .remove_container(
+                id,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>> {
+        let options = ListContainersOptions::<String> {
+            all: true,
+            filters: HashMap::from([("label".to_string(), vec![label.to_string()])]),
+            ..Default::default()
+        };
+
+        Ok(self.docker.list_containers(Some(options)).await?)
+    }
+
+    async fn status(&self, id: &str) -> Result<Option<ContainerStateStatusEnum>> {
+        match self.docker.inspect_container(id, None).await {
+            Ok(info) => Ok(info.state.and_then(|state| state.status)),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn cpu_usage(&self, id: &str) -> Result<Duration> {
+        let stats = self
+            .docker
+            .stats(
+                id,
+                Some(StatsOptions {
+                    stream: false,
+                    one_shot: true,
+                }),
+            )
+            .next()
+            .await
+            .context("No stats returned")??;
+
+        Ok(Duration::from_nanos(stats.cpu_stats.cpu_usage.total_usage))
+    }
+
+    async fn image_digest(&self, reference: &str) -> Result<Option<String>> {
+        let image = match self.docker.inspect_image(reference).await {
+            Ok(image) => image,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(image
+            .repo_digests
+            .and_then(|digests| digests.into_iter().next())
+            .and_then(|digest| digest.split_once('@').map(|(_, digest)| digest.to_string()))
+            .or(image.id))
+    }
+
+    async fn info(&self) -> Result<SystemInfo> {
+        Ok(self.docker.info().await?)
+    }
+
+    async fn pull_image(&self, reference: &str) -> Result<()> {
+        let (from_image, tag) = split_reference(reference);
+        let mut pull = self.docker.create_image(
+            Some(CreateImageOptions {
+                from_image,
+                tag,
+                ..Default::default()
+            }),
+            None,
+            None,
+        );
+
+        while let Some(info) = pull.next().await {
+            let info = info?;
+            if let Some(error) = info.error {
+                return Err(anyhow!(error));
+            }
+            if let Some(status) = info.status {
+                println!(
+                    "{}: {} {} {}",
+                    reference,
+                    info.id.unwrap_or_default(),
+                    status,
+                    info.progress.unwrap_or_default()
+                );
+            }
+        }
+        Ok(())
+    }
+}
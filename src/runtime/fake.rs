@@ -0,0 +1,397 @@
+//! In-memory container runtime for tests.
+//!
+//! Containers only hold a file system. Commands are interpreted by a tiny
+//! shell that knows `echo`, `cat`, `cp`, `fail` and `sleep`, which is enough
+//! to drive the compile and run steps of the languages used in tests.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use bollard::{
+    container::{Config, LogOutput},
+    service::{ContainerStateStatusEnum, ContainerSummary, HostConfig, Runtime, SystemInfo},
+};
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
+use tar::{Archive, Header};
+
+use super::{ContainerRuntime, ExecOptions, LogStream};
+use crate::{hosts::Host, image::ImageState, language::Language};
+
+pub struct FakeContainer {
+    pub name: String,
+    pub image: String,
+    pub labels: HashMap<String, String>,
+    pub status: ContainerStateStatusEnum,
+    pub files: HashMap<String, Vec<u8>>,
+    pub host_config: HostConfig,
+}
+
+#[derive(Default)]
+struct State {
+    containers: HashMap<String, FakeContainer>,
+    execs: Vec<(String, Vec<String>, ExecOptions)>,
+}
+
+#[derive(Default)]
+pub struct FakeRuntime {
+    state: Mutex<State>,
+    /// Images for which container creation fails.
+    pub missing_images: HashSet<String>,
+    /// CPU time reported for every container.
+    pub cpu: Duration,
+    /// OCI runtimes available besides `runc`.
+    pub runtimes: HashSet<String>,
+}
+
+/// A language for tests whose commands the fake understands.
+pub fn language(image: &str, run_command: &str, compile_command: Option<&str>) -> Language {
+    Language {
+        name: image.to_string(),
+        code: vec![image.to_string()],
+        extension: "txt".to_string(),
+        path: "{file}".to_string(),
+        run_command: run_command.to_string(),
+        compile_command: compile_command.map(String::from),
+        image: image.to_string(),
+        image_digest: None,
+        color: None,
+        color_env: vec![],
+        pool_min: 0,
+        pool_max: 0,
+        host_labels: vec![],
+        sandbox: Default::default(),
+        test: None,
+    }
+}
+
+/// A host backed by `runtime` that supports every language.
+pub fn host(runtime: &Arc<FakeRuntime>) -> Arc<Host> {
+    Arc::new(Host::new("test".to_string(), runtime.clone(), 1, vec![]))
+}
+
+/// Marks the image of the test languages named `name` as ready on `host`,
+/// as preparing the images would.
+pub fn with_ready_image(host: Arc<Host>, name: &str) -> Arc<Host> {
+    host.set_image_state(&language(name, "", None), ImageState::Ready);
+    host
+}
+
+/// Container ids are unique across runtimes, like those of real daemons.
+fn next_id() -> String {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    format!("fake-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+fn normalize(path: &str) -> String {
+    path.trim_start_matches("./")
+        .trim_start_matches('/')
+        .to_string()
+}
+
+fn stdout(message: impl Into<String>) -> LogOutput {
+    LogOutput::StdOut {
+        message: message.into().into_bytes().into(),
+    }
+}
+
+fn stderr(message: impl Into<String>) -> LogOutput {
+    LogOutput::StdErr {
+        message: message.into().into_bytes().into(),
+    }
+}
+
+impl FakeRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_missing_image(mut self, image: &str) -> Self {
+        self.missing_images.insert(image.to_string());
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: &str) -> Self {
+        self.runtimes.insert(runtime.to_string());
+        self
+    }
+
+    /// Ids of all containers that currently exist.
+    pub fn container_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .containers
+            .keys()
+            .cloned()
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn exists(&self, id: &str) -> bool {
+        self.state.lock().unwrap().containers.contains_key(id)
+    }
+
+    pub fn file(&self, id: &str, path: &str) -> Option<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .containers
+            .get(id)?
+            .files
+            .get(&normalize(path))
+            .cloned()
+    }
+
+    pub fn host_config(&self, id: &str) -> Option<HostConfig> {
+        Some(
+            self.state
+                .lock()
+                .unwrap()
+                .containers
+                .get(id)?
+                .host_config
+                .clone(),
+        )
+    }
+
+    pub fn labels(&self, id: &str) -> Option<HashMap<String, String>> {
+        Some(
+            self.state
+                .lock()
+                .unwrap()
+                .containers
+                .get(id)?
+                .labels
+                .clone(),
+        )
+    }
+
+    pub fn set_status(&self, id: &str, status: ContainerStateStatusEnum) {
+        if let Some(container) = self.state.lock().unwrap().containers.get_mut(id) {
+            container.status = status;
+        }
+    }
+
+    /// Adds a container directly, as if another process had created it.
+    pub fn insert(&self, labels: HashMap<String, String>) -> String {
+        let mut state = self.state.lock().unwrap();
+        let id = next_id();
+        state.containers.insert(
+            id.clone(),
+            FakeContainer {
+                name: id.clone(),
+                image: String::new(),
+                labels,
+                status: ContainerStateStatusEnum::CREATED,
+                files: HashMap::new(),
+                host_config: HostConfig::default(),
+            },
+        );
+        id
+    }
+
+    /// Commands executed so far, with the container they ran in.
+    pub fn execs(&self) -> Vec<(String, Vec<String>, ExecOptions)> {
+        self.state.lock().unwrap().execs.clone()
+    }
+
+    fn run(
+        files: &mut HashMap<String, Vec<u8>>,
+        cmd: &[String],
+        stdin: Option<Vec<u8>>,
+    ) -> Option<Vec<LogOutput>> {
+        let args = &cmd[1..];
+        let output = match cmd[0].as_str() {
+            "echo" => vec![stdout(format!("{}\n", args.join(" ")))],
+            "cat" if args.is_empty() => {
+                vec![stdout(String::from_utf8_lossy(&stdin.unwrap_or_default()))]
+            }
+            "cat" => args
+                .iter()
+                .map(|path| match files.get(&normalize(path)) {
+                    Some(content) => stdout(String::from_utf8_lossy(content)),
+                    None => stderr(format!("cat: {}: No such file or directory\n", path)),
+                })
+                .collect(),
+            "cp" => match files.get(&normalize(&args[0])).cloned() {
+                Some(content) => {
+                    files.insert(normalize(&args[1]), content);
+                    vec![]
+                }
+                None => vec![stderr(format!("cp: {}: No such file\n", args[0]))],
+            },
+            "fail" => vec![stderr(format!("{}\n", args.join(" ")))],
+            "sleep" => return None,
+            command => vec![stderr(format!("{}: command not found\n", command))],
+        };
+        Some(output)
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for FakeRuntime {
+    async fn create(&self, name: &str, config: Config<String>) -> Result<String> {
+        let image = config.image.unwrap_or_default();
+        if self.missing_images.contains(&image) {
+            return Err(anyhow!("No such image: {}", image));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let id = next_id();
+        state.containers.insert(
+            id.clone(),
+            FakeContainer {
+                name: name.to_string(),
+                image,
+                labels: config.labels.unwrap_or_default(),
+                status: ContainerStateStatusEnum::CREATED,
+                files: HashMap::new(),
+                host_config: config.host_config.unwrap_or_default(),
+            },
+        );
+        Ok(id)
+    }
+
+    async fn start(&self, id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let container = state.containers.get_mut(id).context("No such container")?;
+        container.status = ContainerStateStatusEnum::RUNNING;
+        Ok(())
+    }
+
+    async fn upload(&self, id: &str, archive: Vec<u8>) -> Result<()> {
+        let data = if archive.starts_with(&[0x1f, 0x8b]) {
+            let mut data = vec![];
+            GzDecoder::new(archive.as_slice()).read_to_end(&mut data)?;
+            data
+        } else {
+            archive
+        };
+
+        let mut files = vec![];
+        for entry in Archive::new(data.as_slice()).entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            let mut content = vec![];
+            entry.read_to_end(&mut content)?;
+            files.push((normalize(&path), content));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let container = state.containers.get_mut(id).context("No such container")?;
+        container.files.extend(files);
+        Ok(())
+    }
+
+    async fn exec(&self, id: &str, cmd: Vec<String>, options: ExecOptions) -> Result<LogStream> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .execs
+            .push((id.to_string(), cmd.clone(), options.clone()));
+        let container = state.containers.get_mut(id).context("No such container")?;
+        if container.status != ContainerStateStatusEnum::RUNNING {
+            return Err(anyhow!("Container {} is not running", id));
+        }
+
+        Ok(match Self::run(&mut container.files, &cmd, options.stdin) {
+            Some(output) => futures_util::stream::iter(output.into_iter().map(Ok)).boxed(),
+            None => futures_util::stream::pending().boxed(),
+        })
+    }
+
+    async fn download(&self, id: &str, path: &str) -> Result<Vec<u8>> {
+        let content = self.file(id, path).context("No such file")?;
+
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = Header::new_gnu();
+        header.set_path(normalize(path))?;
+        header.set_size(content.len() as u64);
+        header.set_cksum();
+        tar.append(&header, content.as_slice())?;
+        Ok(tar.into_inner()?)
+    }
+
+    async fn remove(&self, id: &str) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .containers
+            .remove(id)
+            .map(|_| ())
+            .context("No such container")
+    }
+
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>> {
+        let (key, value) = label.split_once('=').unwrap_or((label, ""));
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .containers
+            .iter()
+            .filter(|(_, container)| container.labels.get(key).map(String::as_str) == Some(value))
+            .map(|(id, container)| ContainerSummary {
+                id: Some(id.clone()),
+                names: Some(vec![format!("/{}", container.name)]),
+                image: Some(container.image.clone()),
+                labels: Some(container.labels.clone()),
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn status(&self, id: &str) -> Result<Option<ContainerStateStatusEnum>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .containers
+            .get(id)
+            .map(|container| container.status))
+    }
+
+    async fn image_digest(&self, reference: &str) -> Result<Option<String>> {
+        if self.missing_images.contains(reference) {
+            return Ok(None);
+        }
+        Ok(Some(format!("sha256:{}", reference)))
+    }
+
+    async fn pull_image(&self, reference: &str) -> Result<()> {
+        Err(anyhow!("pull access denied for {}", reference))
+    }
+
+    /// Reports the extra runtimes and seccomp, but no AppArmor support.
+    async fn info(&self) -> Result<SystemInfo> {
+        let runtimes = self
+            .runtimes
+            .iter()
+            .map(String::as_str)
+            .chain(["runc"])
+            .map(|name| (name.to_string(), Runtime::default()))
+            .collect();
+        Ok(SystemInfo {
+            runtimes: Some(runtimes),
+            default_runtime: Some("runc".to_string()),
+            security_options: Some(vec!["name=seccomp,profile=builtin".to_string()]),
+            ..Default::default()
+        })
+    }
+
+    async fn cpu_usage(&self, id: &str) -> Result<Duration> {
+        if !self.exists(id) {
+            return Err(anyhow!("No such container"));
+        }
+        Ok(self.cpu)
+    }
+}
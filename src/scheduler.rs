@@ -0,0 +1,351 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+/// Caps on how many jobs may execute at the same time.
+#[derive(Clone, Copy, Debug)]
+pub struct SchedulerLimits {
+    pub global: usize,
+    pub per_user: usize,
+    pub per_guild: usize,
+}
+
+struct QueuedJob {
+    id: u64,
+    user: u64,
+    guild: Option<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    queue: VecDeque<QueuedJob>,
+    running: usize,
+    per_user: HashMap<u64, usize>,
+    per_guild: HashMap<u64, usize>,
+}
+
+/// FIFO job queue in front of the container pool.
+///
+/// Jobs are admitted in arrival order as long as the global, per-user and
+/// per-guild limits allow it. A job blocked by its own user or guild limit
+/// does not hold back jobs queued behind it.
+#[derive(Clone)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+    notify: Arc<Notify>,
+    limits: SchedulerLimits,
+}
+
+pub enum TicketUpdate {
+    /// The job is waiting; the position is 1-based.
+    Queued(usize),
+    Admitted(Permit),
+}
+
+/// A job waiting in the queue. Dropping it gives up the place in the queue.
+pub struct Ticket {
+    id: u64,
+    user: u64,
+    guild: Option<u64>,
+    scheduler: Scheduler,
+    last_position: Option<usize>,
+}
+
+/// Allows one job to run. The slot is freed when the permit is dropped.
+pub struct Permit {
+    user: u64,
+    guild: Option<u64>,
+    scheduler: Scheduler,
+}
+
+impl Scheduler {
+    pub fn new(limits: SchedulerLimits) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::default())),
+            notify: Arc::new(Notify::new()),
+            limits,
+        }
+    }
+
+    pub fn enqueue(&self, user: u64, guild: Option<u64>) -> Ticket {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push_back(QueuedJob { id, user, guild });
+
+        Ticket {
+            id,
+            user,
+            guild,
+            scheduler: self.clone(),
+            last_position: None,
+        }
+    }
+
+    fn is_eligible(&self, state: &State, user: u64, guild: Option<u64>) -> bool {
+        let user_running = state.per_user.get(&user).copied().unwrap_or_default();
+        let guild_running = guild
+            .and_then(|guild| state.per_guild.get(&guild).copied())
+            .unwrap_or_default();
+
+        user_running < self.limits.per_user && guild_running < self.limits.per_guild
+    }
+
+    /// Admits the job if it is the first eligible one in the queue, otherwise
+    /// returns its 1-based position.
+    fn try_admit(&self, id: u64) -> Result<(), usize> {
+        let mut state = self.state.lock().unwrap();
+        let Some(position) = state.queue.iter().position(|job| job.id == id) else {
+            return Err(0);
+        };
+
+        if state.running < self.limits.global {
+            let first_eligible = state
+                .queue
+                .iter()
+                .position(|job| self.is_eligible(&state, job.user, job.guild));
+            if first_eligible == Some(position) {
+                let job = state.queue.remove(position).unwrap();
+                state.running += 1;
+                *state.per_user.entry(job.user).or_default() += 1;
+                if let Some(guild) = job.guild {
+                    *state.per_guild.entry(guild).or_default() += 1;
+                }
+                return Ok(());
+            }
+        }
+
+        Err(position + 1)
+    }
+
+    fn release(&self, user: u64, guild: Option<u64>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.running = state.running.saturating_sub(1);
+            decrement(&mut state.per_user, user);
+            if let Some(guild) = guild {
+                decrement(&mut state.per_guild, guild);
+            }
+        }
+        self.notify.notify_waiters();
+    }
+}
+
+/// Lowers the count of `key`, forgetting it at zero so that one-off users
+/// such as API requests do not pile up.
+fn decrement(counts: &mut HashMap<u64, usize>, key: u64) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+impl Ticket {
+    /// Waits until the job is admitted or its queue position changes.
+    pub async fn next(&mut self) -> TicketUpdate {
+        loop {
+            let notified = self.scheduler.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match self.scheduler.try_admit(self.id) {
+                Ok(()) => {
+                    // Admission may unblock jobs that were behind us.
+                    self.scheduler.notify.notify_waiters();
+                    return TicketUpdate::Admitted(Permit {
+                        user: self.user,
+                        guild: self.guild,
+                        scheduler: self.scheduler.clone(),
+                    });
+                }
+                Err(position) if self.last_position != Some(position) => {
+                    self.last_position = Some(position);
+                    return TicketUpdate::Queued(position);
+                }
+                Err(_) => notified.await,
+            }
+        }
+    }
+
+    /// Waits until the job is admitted, ignoring queue positions.
+    pub async fn admitted(mut self) -> Permit {
+        loop {
+            if let TicketUpdate::Admitted(permit) = self.next().await {
+                return permit;
+            }
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let removed = {
+            let mut state = self.scheduler.state.lock().unwrap();
+            let before = state.queue.len();
+            state.queue.retain(|job| job.id != self.id);
+            before != state.queue.len()
+        };
+        if removed {
+            self.scheduler.notify.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.release(self.user, self.guild);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn scheduler(global: usize, per_user: usize, per_guild: usize) -> Scheduler {
+        Scheduler::new(SchedulerLimits {
+            global,
+            per_user,
+            per_guild,
+        })
+    }
+
+    /// The update the ticket has ready, if any.
+    fn poll(ticket: &mut Ticket) -> Option<TicketUpdate> {
+        ticket.next().now_or_never()
+    }
+
+    fn admit(ticket: &mut Ticket) -> Permit {
+        match poll(ticket) {
+            Some(TicketUpdate::Admitted(permit)) => permit,
+            _ => panic!("job was not admitted"),
+        }
+    }
+
+    fn position(ticket: &mut Ticket) -> Option<usize> {
+        match poll(ticket) {
+            Some(TicketUpdate::Queued(position)) => Some(position),
+            Some(TicketUpdate::Admitted(_)) => panic!("job was admitted"),
+            None => None,
+        }
+    }
+
+    #[test]
+    fn admits_in_arrival_order() {
+        let scheduler = scheduler(1, 1, 1);
+        let mut first = scheduler.enqueue(1, None);
+        let mut second = scheduler.enqueue(2, None);
+        let mut third = scheduler.enqueue(3, None);
+
+        // The second job cannot take the free slot ahead of the first.
+        assert_eq!(position(&mut second), Some(2));
+        let permit = admit(&mut first);
+        assert_eq!(position(&mut third), Some(2));
+
+        drop(permit);
+        assert_eq!(position(&mut third), None);
+        let permit = admit(&mut second);
+        drop(permit);
+        admit(&mut third);
+    }
+
+    #[test]
+    fn caps_jobs_per_user() {
+        let scheduler = scheduler(4, 1, 4);
+        let mut first = scheduler.enqueue(1, Some(10));
+        let mut second = scheduler.enqueue(1, Some(10));
+        let mut other = scheduler.enqueue(2, Some(10));
+
+        let permit = admit(&mut first);
+        assert_eq!(position(&mut second), Some(1));
+        // A job blocked by its user does not hold back the ones behind it.
+        let _other = admit(&mut other);
+
+        drop(permit);
+        admit(&mut second);
+    }
+
+    #[test]
+    fn caps_jobs_per_guild() {
+        let scheduler = scheduler(4, 4, 2);
+        let mut jobs: Vec<Ticket> = (1..=3)
+            .map(|user| scheduler.enqueue(user, Some(10)))
+            .collect();
+        let mut direct = scheduler.enqueue(4, None);
+        let mut other_guild = scheduler.enqueue(5, Some(20));
+
+        let first = admit(&mut jobs[0]);
+        let _second = admit(&mut jobs[1]);
+        assert_eq!(position(&mut jobs[2]), Some(1));
+        let _direct = admit(&mut direct);
+        let _other_guild = admit(&mut other_guild);
+
+        drop(first);
+        admit(&mut jobs[2]);
+    }
+
+    #[test]
+    fn caps_jobs_globally() {
+        let scheduler = scheduler(1, 4, 4);
+        let mut first = scheduler.enqueue(1, Some(10));
+        let mut second = scheduler.enqueue(2, Some(20));
+
+        let permit = admit(&mut first);
+        assert_eq!(position(&mut second), Some(1));
+        drop(permit);
+        admit(&mut second);
+    }
+
+    #[test]
+    fn reports_queue_positions() {
+        let scheduler = scheduler(1, 1, 1);
+        let _running = admit(&mut scheduler.enqueue(1, None));
+        let mut first = scheduler.enqueue(2, None);
+        let mut second = scheduler.enqueue(3, None);
+        let mut third = scheduler.enqueue(4, None);
+
+        assert_eq!(position(&mut first), Some(1));
+        assert_eq!(position(&mut second), Some(2));
+        assert_eq!(position(&mut third), Some(3));
+        // Unchanged positions are not reported again.
+        assert_eq!(position(&mut third), None);
+
+        // Leaving the queue moves everyone behind forward.
+        drop(first);
+        assert_eq!(position(&mut second), Some(1));
+        assert_eq!(position(&mut third), Some(2));
+    }
+
+    #[tokio::test]
+    async fn wakes_waiting_jobs() {
+        let scheduler = scheduler(1, 1, 1);
+        let permit = admit(&mut scheduler.enqueue(1, None));
+        let mut waiting = scheduler.enqueue(2, None);
+        assert_eq!(position(&mut waiting), Some(1));
+
+        let admitted =
+            tokio::spawn(async move { matches!(waiting.next().await, TicketUpdate::Admitted(_)) });
+        tokio::task::yield_now().await;
+        drop(permit);
+        assert!(admitted.await.unwrap());
+    }
+
+    #[test]
+    fn forgets_idle_users_and_guilds() {
+        let scheduler = scheduler(4, 4, 4);
+        let permit = admit(&mut scheduler.enqueue(1, Some(10)));
+        drop(permit);
+
+        let state = scheduler.state.lock().unwrap();
+        assert_eq!(state.running, 0);
+        assert!(state.per_user.is_empty());
+        assert!(state.per_guild.is_empty());
+    }
+}
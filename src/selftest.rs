@@ -0,0 +1,292 @@
+//! Runs the test program of every language to catch broken images and
+//! commands.
+
+use std::{fmt, time::Duration};
+
+use futures_util::future::join_all;
+
+use crate::{
+    executor::{Executor, Job, JobLimits},
+    image::{ImageManager, ImageState},
+    language::Language,
+    scheduler::Scheduler,
+};
+
+/// Guild under which tests are scheduled, so that the per-guild limit
+/// bounds them. Discord never uses 1 as an id.
+const SELFTEST_GUILD: u64 = 1;
+/// Scheduler users of tests start here, above every Discord id, so that
+/// tests of different languages may run at the same time.
+const SELFTEST_USER_BASE: u64 = 1 << 62;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// The test could not run, for example because no test is configured.
+    Skipped(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct TestReport {
+    pub language: String,
+    pub outcome: Outcome,
+    pub compile_time: Option<Duration>,
+    pub run_time: Option<Duration>,
+    pub digest: Option<String>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Passed => write!(f, "PASS {}", self.language)?,
+            Outcome::Failed(reason) => write!(f, "FAIL {}: {}", self.language, reason)?,
+            Outcome::Skipped(reason) => write!(f, "SKIP {}: {}", self.language, reason)?,
+        }
+        if let Some(compile_time) = self.compile_time {
+            write!(f, ", compile {:.2}s", compile_time.as_secs_f64())?;
+        }
+        if let Some(run_time) = self.run_time {
+            write!(f, ", run {:.2}s", run_time.as_secs_f64())?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, ", image {}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tests all `languages`, as many at a time as `scheduler` admits.
+pub async fn run_all(
+    executor: &Executor,
+    scheduler: &Scheduler,
+    images: &ImageManager,
+    languages: &[Language],
+    limits: JobLimits,
+) -> Vec<TestReport> {
+    join_all(
+        languages
+            .iter()
+            .enumerate()
+            .map(|(i, language)| async move {
+                let _permit = scheduler
+                    .enqueue(SELFTEST_USER_BASE + i as u64, Some(SELFTEST_GUILD))
+                    .admitted()
+                    .await;
+                run(executor, images, language, limits).await
+            }),
+    )
+    .await
+}
+
+/// One line per language followed by a summary.
+pub fn summarize(reports: &[TestReport]) -> String {
+    let mut summary: String = reports
+        .iter()
+        .map(|report| format!("{}\n", report))
+        .collect();
+    summary += &format!(
+        "{} of {} languages passed",
+        reports.iter().filter(|report| report.passed()).count(),
+        reports.len()
+    );
+    summary
+}
+
+/// Runs the test program of `language` and compares its output.
+///
+/// Exit codes are not reported, so a program that writes to stderr fails
+/// too: a missing command or a program that failed to compile does. The
+/// compiler may write warnings there.
+pub async fn run(
+    executor: &Executor,
+    images: &ImageManager,
+    language: &Language,
+    limits: JobLimits,
+) -> TestReport {
+    let mut report = TestReport {
+        language: language.name.clone(),
+        outcome: Outcome::Passed,
+        compile_time: None,
+        run_time: None,
+        digest: images.digest(language).await,
+    };
+
+    let Some(test) = &language.test else {
+        report.outcome = Outcome::Skipped("no test configured".to_string());
+        return report;
+    };
+    if images.state(language) != ImageState::Ready {
+        report.outcome = Outcome::Skipped("image unavailable".to_string());
+        return report;
+    }
+
+    let job = Job {
+        language: language.clone(),
+        source: test.source.clone(),
+        files: vec![],
+        stdin: None,
+        downloads: vec![],
+        color: false,
+        limits,
+    };
+    let result = match executor.run(job).await {
+        Ok(result) => result,
+        Err(e) => {
+            report.outcome = Outcome::Failed(e.to_string());
+            return report;
+        }
+    };
+    report.compile_time = result.compile_time;
+    report.run_time = Some(result.run_time);
+
+    let output = result.output.trim_end();
+    if result.timed_out {
+        report.outcome = Outcome::Failed("timeout".to_string());
+    } else if result.stderr {
+        report.outcome = Outcome::Failed(format!("wrote to stderr {:?}", output));
+    } else if output != test.expected_output.trim_end() {
+        let mut reason = format!("unexpected output {:?}", output);
+        if !result.compile_output.is_empty() {
+            reason += &format!(", compile log {:?}", result.compile_output.trim_end());
+        }
+        report.outcome = Outcome::Failed(reason);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        container_pool::ContainerPool,
+        language::LanguageTest,
+        runtime::fake::{self, FakeRuntime},
+        scheduler::SchedulerLimits,
+    };
+
+    fn language(name: &str, run_command: &str, expected_output: &str) -> Language {
+        Language {
+            test: Some(LanguageTest {
+                source: "hello".to_string(),
+                expected_output: expected_output.to_string(),
+            }),
+            ..fake::language(name, run_command, None)
+        }
+    }
+
+    fn limits() -> JobLimits {
+        JobLimits {
+            timeout: Duration::from_secs(5),
+            output_bytes: 1024,
+        }
+    }
+
+    fn scheduler(global: usize) -> Scheduler {
+        Scheduler::new(SchedulerLimits {
+            global,
+            per_user: 1,
+            per_guild: global,
+        })
+    }
+
+    async fn setup(languages: &[Language]) -> (Executor, ImageManager) {
+        let runtime = Arc::new(FakeRuntime::new());
+        let host = fake::host(&runtime);
+        let executor = Executor::new(ContainerPool::new(
+            vec![host.clone()],
+            "test".to_string(),
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        ));
+        let images = ImageManager::new(vec![host]);
+        images.prepare(languages).await;
+        (executor, images)
+    }
+
+    #[tokio::test]
+    async fn reports_each_language() {
+        let runtime = Arc::new(FakeRuntime::new().with_missing_image("missing"));
+        let host = fake::host(&runtime);
+        let executor = Executor::new(ContainerPool::new(
+            vec![host.clone()],
+            "test".to_string(),
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        ));
+        let images = ImageManager::new(vec![host]);
+        let languages = vec![
+            language("good", "cat {file}", "hello\n"),
+            language("bad", "echo bye", "hello"),
+            fake::language("untested", "cat {file}", None),
+            language("missing", "cat {file}", "hello"),
+        ];
+        images.prepare(&languages).await;
+
+        let scheduler = scheduler(4);
+        let reports = run_all(&executor, &scheduler, &images, &languages, limits()).await;
+        assert_eq!(reports[0].outcome, Outcome::Passed);
+        assert!(reports[0].run_time.is_some());
+        assert_eq!(reports[0].digest.as_deref(), Some("sha256:good"));
+        assert_eq!(
+            reports[1].outcome,
+            Outcome::Failed("unexpected output \"bye\"".to_string())
+        );
+        assert!(matches!(reports[2].outcome, Outcome::Skipped(_)));
+        assert!(matches!(reports[3].outcome, Outcome::Skipped(_)));
+        assert!(summarize(&reports).ends_with("1 of 4 languages passed"));
+        assert!(runtime.container_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_programs_writing_to_stderr() {
+        let missing = language("missing", "python3 {file}", "hello");
+        // The program is missing after a failed compile.
+        let failing = Language {
+            compile_command: Some("fail error".to_string()),
+            ..language("failing", "cat out", "hello")
+        };
+        let warning = Language {
+            compile_command: Some("fail warning".to_string()),
+            ..language("warning", "cat {file}", "hello")
+        };
+        let languages = [missing, failing, warning];
+        let (executor, images) = setup(&languages).await;
+
+        let limits = limits();
+        let report = run(&executor, &images, &languages[0], limits).await;
+        assert_eq!(
+            report.outcome,
+            Outcome::Failed("wrote to stderr \"python3: command not found\"".to_string())
+        );
+        let report = run(&executor, &images, &languages[1], limits).await;
+        assert!(matches!(report.outcome, Outcome::Failed(_)));
+        // Compiler warnings are accepted.
+        let report = run(&executor, &images, &languages[2], limits).await;
+        assert_eq!(report.outcome, Outcome::Passed);
+    }
+
+    #[tokio::test]
+    async fn waits_for_scheduler() {
+        let languages = [language("good", "cat {file}", "hello")];
+        let (executor, images) = setup(&languages).await;
+        let scheduler = scheduler(1);
+        let permit = scheduler.enqueue(1, None).admitted().await;
+
+        let reports = run_all(&executor, &scheduler, &images, &languages, limits());
+        tokio::pin!(reports);
+        let waited = tokio::time::timeout(Duration::from_millis(50), &mut reports).await;
+        assert!(waited.is_err());
+
+        drop(permit);
+        assert_eq!(reports.await[0].outcome, Outcome::Passed);
+    }
+}